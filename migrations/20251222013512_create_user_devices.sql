@@ -0,0 +1,13 @@
+CREATE TABLE user_devices (
+                              public_key BYTEA NOT NULL,
+                              device_id BYTEA NOT NULL,
+                              created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                              last_login_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                              PRIMARY KEY (public_key, device_id)
+);
+
+-- Rows without a device predate multi-device support and go to whichever device logs in first.
+ALTER TABLE pending_messages
+    ADD COLUMN recipient_device_id BYTEA;
+
+CREATE INDEX idx_pending_recipient_device ON pending_messages(recipient_pubkey, recipient_device_id);
@@ -0,0 +1,19 @@
+CREATE TABLE groups (
+                        id BIGSERIAL PRIMARY KEY,
+                        name TEXT NOT NULL,
+                        created_by BYTEA NOT NULL,
+                        created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE group_members (
+                               group_id BIGINT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+                               public_key BYTEA NOT NULL,
+                               role TEXT NOT NULL DEFAULT 'member' CHECK (role IN ('admin', 'member')),
+                               joined_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                               PRIMARY KEY (group_id, public_key)
+);
+
+CREATE INDEX idx_group_members_pubkey ON group_members(public_key);
+
+ALTER TABLE pending_messages
+    ADD COLUMN group_id BIGINT;
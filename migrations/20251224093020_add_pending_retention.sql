@@ -0,0 +1,7 @@
+ALTER TABLE users
+    ADD COLUMN pending_retention_days INTEGER CHECK (pending_retention_days > 0);
+
+CREATE INDEX idx_pending_created_at ON pending_messages(created_at);
+
+-- Retention now runs inside the server with per-recipient periods.
+DROP FUNCTION IF EXISTS cleanup_old_pending_messages();
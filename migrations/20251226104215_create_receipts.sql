@@ -0,0 +1,19 @@
+ALTER TABLE users
+    ADD COLUMN read_receipts BOOLEAN NOT NULL DEFAULT TRUE;
+
+-- Lets the recipient refer to a message the way its sender knows it.
+ALTER TABLE pending_messages
+    ADD COLUMN client_message_id BIGINT NOT NULL DEFAULT 0;
+
+CREATE TABLE pending_receipts (
+                                  id BIGSERIAL PRIMARY KEY,
+                                  recipient_pubkey BYTEA NOT NULL,
+                                  recipient_device_id BYTEA NOT NULL,
+                                  reader_pubkey BYTEA NOT NULL,
+                                  client_message_ids BIGINT[] NOT NULL,
+                                  kind SMALLINT NOT NULL CHECK (kind IN (1, 2)),
+                                  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_pending_receipts_recipient_device ON pending_receipts(recipient_pubkey, recipient_device_id);
+CREATE INDEX idx_pending_receipts_created_at ON pending_receipts(created_at);
@@ -0,0 +1,4 @@
+ALTER TABLE users
+    ADD COLUMN last_seen_at TIMESTAMPTZ,
+    -- 0 = everyone, 1 = users sharing a group, 2 = nobody
+    ADD COLUMN last_seen_visibility SMALLINT NOT NULL DEFAULT 0 CHECK (last_seen_visibility IN (0, 1, 2));
@@ -0,0 +1,8 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+ALTER TABLE users
+    ADD COLUMN discoverable_by_name BOOLEAN NOT NULL DEFAULT TRUE;
+
+CREATE INDEX idx_users_username_trgm ON users USING GIN (lower(username) gin_trgm_ops);
+CREATE INDEX idx_users_first_name_trgm ON users USING GIN (lower(first_name) gin_trgm_ops);
+CREATE INDEX idx_users_last_name_trgm ON users USING GIN (lower(last_name) gin_trgm_ops);
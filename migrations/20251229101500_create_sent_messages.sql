@@ -0,0 +1,11 @@
+-- Which sender messaged which recipient, so receipts can only name messages the reader really got.
+-- Kept for the longest retention period a recipient may pick, then purged.
+CREATE TABLE sent_messages (
+                               sender_pubkey BYTEA NOT NULL,
+                               recipient_pubkey BYTEA NOT NULL,
+                               client_message_id BIGINT NOT NULL,
+                               created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                               PRIMARY KEY (sender_pubkey, recipient_pubkey, client_message_id)
+);
+
+CREATE INDEX idx_sent_messages_created_at ON sent_messages(created_at);
@@ -0,0 +1,4 @@
+-- Kind 3 tells a sender their messages expired before reaching the recipient.
+ALTER TABLE pending_receipts
+    DROP CONSTRAINT pending_receipts_kind_check,
+    ADD CONSTRAINT pending_receipts_kind_check CHECK (kind IN (1, 2, 3));
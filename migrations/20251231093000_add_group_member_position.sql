@@ -0,0 +1,3 @@
+-- Members added in one transaction share joined_at, the position keeps their order.
+ALTER TABLE group_members
+    ADD COLUMN position BIGSERIAL;
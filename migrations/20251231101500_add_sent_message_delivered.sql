@@ -0,0 +1,3 @@
+-- Set once any device of the recipient acks a copy, expired copies of delivered messages are not reported.
+ALTER TABLE sent_messages
+    ADD COLUMN delivered BOOLEAN NOT NULL DEFAULT FALSE;
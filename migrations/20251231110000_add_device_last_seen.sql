@@ -0,0 +1,5 @@
+-- Bumped on login and disconnect, devices not seen for retention.device_expiry_days are removed.
+ALTER TABLE user_devices
+    RENAME COLUMN last_login_at TO last_seen_at;
+
+CREATE INDEX idx_user_devices_last_seen ON user_devices(last_seen_at);
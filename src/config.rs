@@ -0,0 +1,632 @@
+use crate::logging::{LogFormat, LogLevel};
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DEFAULT_CONFIG_PATH: &str = "hnet.toml";
+
+#[derive(Parser, Debug, Clone)]
+#[command(version, about = "hnet messenger server")]
+pub struct Cli {
+    /// Path to the TOML configuration file
+    #[arg(long, env = "HNET_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Apply pending database migrations and exit
+    #[arg(long)]
+    pub migrate_only: bool,
+
+    #[arg(long, env = "HNET_HOST")]
+    pub host: Option<String>,
+
+    #[arg(long, env = "HNET_PORT")]
+    pub port: Option<u16>,
+
+    #[arg(long, env = "WS_PORT")]
+    pub ws_port: Option<u16>,
+
+    #[arg(long, env = "TLS_CERT_PATH")]
+    pub tls_cert_path: Option<PathBuf>,
+
+    #[arg(long, env = "TLS_KEY_PATH")]
+    pub tls_key_path: Option<PathBuf>,
+
+    #[arg(long, env = "STORAGE", value_enum)]
+    pub storage: Option<StorageBackend>,
+
+    #[arg(long, env = "DATABASE_URL", hide_env_values = true)]
+    pub database_url: Option<String>,
+
+    #[arg(long, env = "HNET_DB_MAX_CONNECTIONS")]
+    pub db_max_connections: Option<u32>,
+
+    #[arg(long, env = "HNET_ENC_PUBKEY_CACHE_SIZE")]
+    pub enc_pubkey_cache_size: Option<usize>,
+
+    #[arg(long, env = "HNET_IDLE_TIMEOUT_SECS")]
+    pub idle_timeout_secs: Option<u64>,
+
+    #[arg(long, env = "HNET_PING_AFTER_SECS")]
+    pub ping_after_secs: Option<u64>,
+
+    #[arg(long, env = "HNET_PURGE_INTERVAL_SECS")]
+    pub purge_interval_secs: Option<u64>,
+
+    #[arg(long, env = "HNET_PENDING_RETENTION_DAYS")]
+    pub pending_retention_days: Option<u32>,
+
+    /// Serve Prometheus metrics on this port, off unless set
+    #[arg(long, env = "HNET_METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+
+    #[arg(long, env = "HNET_LOG_LEVEL", value_enum)]
+    pub log_level: Option<LogLevel>,
+
+    #[arg(long, env = "HNET_LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// Write logs to this file instead of stdout
+    #[arg(long, env = "HNET_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Postgres,
+    Memory,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub session: SessionConfig,
+    pub retention: RetentionConfig,
+    pub quota: QuotaConfig,
+    pub rate_limit: RateLimitConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub ws_port: Option<u16>,
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    pub shutdown_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub storage: StorageBackend,
+    pub url: Option<String>,
+    pub max_connections: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    pub enc_pubkey_cache_size: usize,
+    pub idle_timeout_secs: u64,
+    /// 0 turns server pings off.
+    pub ping_after_secs: u64,
+    pub reap_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub purge_interval_secs: u64,
+    pub default_days: u32,
+    pub max_days: u32,
+    /// Devices that have not connected for this long are removed, with the messages queued for them.
+    pub device_expiry_days: u32,
+    pub batch_size: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub max_message_bytes: u32,
+    pub max_pending_messages: u32,
+    pub max_pending_bytes: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub max_connections_per_ip: usize,
+    /// Throttled packets in a row a connection may send before it is dropped.
+    pub max_violations: u32,
+    /// Every frame from an address, charged before it is decoded.
+    pub frames: BucketConfig,
+    pub auth: BucketConfig,
+    pub search: BucketConfig,
+    pub message: BucketConfig,
+    pub other: BucketConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketConfig {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub host: String,
+    /// The HTTP listener only starts when a port is set.
+    pub port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+    /// Per-module overrides of `level`, keyed by module name such as "network".
+    pub modules: BTreeMap<String, LogLevel>,
+    pub file: Option<PathBuf>,
+    pub max_file_bytes: u64,
+    /// Rotated files kept next to the current one.
+    pub max_files: u32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 8123,
+            ws_port: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            shutdown_timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            storage: StorageBackend::Postgres,
+            url: None,
+            max_connections: 150,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            enc_pubkey_cache_size: 10_000,
+            idle_timeout_secs: 90,
+            ping_after_secs: 30,
+            reap_interval_secs: 5,
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            purge_interval_secs: 3600,
+            default_days: 30,
+            max_days: 365,
+            device_expiry_days: 90,
+            batch_size: 1000,
+        }
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: 64 * 1024,
+            max_pending_messages: 1000,
+            max_pending_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_connections_per_ip: 16,
+            max_violations: 20,
+            frames: BucketConfig::new(100.0, 400),
+            auth: BucketConfig::new(0.5, 5),
+            search: BucketConfig::new(2.0, 10),
+            message: BucketConfig::new(20.0, 50),
+            other: BucketConfig::new(20.0, 100),
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: None,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::DEFAULT,
+            format: LogFormat::Text,
+            modules: BTreeMap::new(),
+            file: None,
+            max_file_bytes: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "cannot parse {}: {}", path.display(), e),
+            ConfigError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Defaults, then the TOML file, then environment and CLI, each layer overriding the last.
+    pub fn load(cli: &Cli) -> Result<Self, ConfigError> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_overrides(cli);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+
+        toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    fn apply_overrides(&mut self, cli: &Cli) {
+        if let Some(host) = &cli.host {
+            self.server.host = host.clone();
+        }
+        if let Some(port) = cli.port {
+            self.server.port = port;
+        }
+        if let Some(ws_port) = cli.ws_port {
+            self.server.ws_port = Some(ws_port);
+        }
+        if let Some(path) = &cli.tls_cert_path {
+            self.server.tls_cert_path = Some(path.clone());
+        }
+        if let Some(path) = &cli.tls_key_path {
+            self.server.tls_key_path = Some(path.clone());
+        }
+        if let Some(storage) = cli.storage {
+            self.database.storage = storage;
+        }
+        if let Some(url) = &cli.database_url {
+            self.database.url = Some(url.clone());
+        }
+        if let Some(max_connections) = cli.db_max_connections {
+            self.database.max_connections = max_connections;
+        }
+        if let Some(size) = cli.enc_pubkey_cache_size {
+            self.session.enc_pubkey_cache_size = size;
+        }
+        if let Some(secs) = cli.idle_timeout_secs {
+            self.session.idle_timeout_secs = secs;
+        }
+        if let Some(secs) = cli.ping_after_secs {
+            self.session.ping_after_secs = secs;
+        }
+        if let Some(secs) = cli.purge_interval_secs {
+            self.retention.purge_interval_secs = secs;
+        }
+        if let Some(days) = cli.pending_retention_days {
+            self.retention.default_days = days;
+        }
+        if let Some(port) = cli.metrics_port {
+            self.metrics.port = Some(port);
+        }
+        if let Some(level) = cli.log_level {
+            self.log.level = level;
+        }
+        if let Some(format) = cli.log_format {
+            self.log.format = format;
+        }
+        if let Some(path) = &cli.log_file {
+            self.log.file = Some(path.clone());
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::Invalid(reason.to_string()));
+
+        if self.server.host.is_empty() {
+            return invalid("server.host must not be empty");
+        }
+        if self.server.port == 0 {
+            return invalid("server.port must not be 0");
+        }
+        if self.server.ws_port == Some(self.server.port) {
+            return invalid("server.ws_port must differ from server.port");
+        }
+        if self.server.ws_port == Some(0) {
+            return invalid("server.ws_port must not be 0");
+        }
+        if self.server.tls_cert_path.is_some() != self.server.tls_key_path.is_some() {
+            return invalid("server.tls_cert_path and server.tls_key_path must be set together");
+        }
+        if self.database.storage == StorageBackend::Postgres && self.database.url.is_none() {
+            return invalid("database.url (or DATABASE_URL) is required for postgres storage");
+        }
+        if self.database.max_connections == 0 {
+            return invalid("database.max_connections must be greater than 0");
+        }
+        if self.session.enc_pubkey_cache_size == 0 {
+            return invalid("session.enc_pubkey_cache_size must be greater than 0");
+        }
+        if self.session.idle_timeout_secs == 0 {
+            return invalid("session.idle_timeout_secs must be greater than 0");
+        }
+        if self.session.ping_after_secs >= self.session.idle_timeout_secs {
+            return invalid("session.ping_after_secs must be less than session.idle_timeout_secs");
+        }
+        if self.session.reap_interval_secs == 0 {
+            return invalid("session.reap_interval_secs must be greater than 0");
+        }
+        if self.retention.purge_interval_secs == 0 {
+            return invalid("retention.purge_interval_secs must be greater than 0");
+        }
+        if self.retention.default_days == 0 {
+            return invalid("retention.default_days must be greater than 0");
+        }
+        if self.retention.max_days > i32::MAX as u32 {
+            return invalid("retention.max_days is too large");
+        }
+        if self.retention.default_days > self.retention.max_days {
+            return invalid("retention.default_days must not exceed retention.max_days");
+        }
+        if self.retention.device_expiry_days == 0 {
+            return invalid("retention.device_expiry_days must be greater than 0");
+        }
+        if self.retention.device_expiry_days > i32::MAX as u32 {
+            return invalid("retention.device_expiry_days is too large");
+        }
+        if self.retention.batch_size == 0 {
+            return invalid("retention.batch_size must be greater than 0");
+        }
+        if self.quota.max_message_bytes == 0 {
+            return invalid("quota.max_message_bytes must be greater than 0");
+        }
+        if self.quota.max_pending_messages == 0 {
+            return invalid("quota.max_pending_messages must be greater than 0");
+        }
+        if self.quota.max_pending_bytes < self.quota.max_message_bytes.into() {
+            return invalid("quota.max_pending_bytes must be at least quota.max_message_bytes");
+        }
+        if self.quota.max_pending_bytes > i64::MAX as u64 {
+            return invalid("quota.max_pending_bytes is too large");
+        }
+        if self.rate_limit.max_connections_per_ip == 0 {
+            return invalid("rate_limit.max_connections_per_ip must be greater than 0");
+        }
+        if self.rate_limit.max_violations == 0 {
+            return invalid("rate_limit.max_violations must be greater than 0");
+        }
+        for (name, bucket) in [
+            ("frames", &self.rate_limit.frames),
+            ("auth", &self.rate_limit.auth),
+            ("search", &self.rate_limit.search),
+            ("message", &self.rate_limit.message),
+            ("other", &self.rate_limit.other),
+        ] {
+            if !(bucket.per_second.is_finite() && bucket.per_second > 0.0) || bucket.burst == 0 {
+                return Err(ConfigError::Invalid(format!(
+                    "rate_limit.{} needs per_second and burst greater than 0",
+                    name
+                )));
+            }
+        }
+
+        if self.metrics.host.is_empty() {
+            return invalid("metrics.host must not be empty");
+        }
+        if self.metrics.port == Some(0) {
+            return invalid("metrics.port must not be 0");
+        }
+        if self.log.max_file_bytes == 0 {
+            return invalid("log.max_file_bytes must be greater than 0");
+        }
+        if self.log.max_files == 0 {
+            return invalid("log.max_files must be greater than 0");
+        }
+
+        Ok(())
+    }
+}
+
+impl ServerConfig {
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+}
+
+impl SessionConfig {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    pub fn ping_after(&self) -> Option<Duration> {
+        (self.ping_after_secs > 0).then(|| Duration::from_secs(self.ping_after_secs))
+    }
+
+    pub fn reap_interval(&self) -> Duration {
+        Duration::from_secs(self.reap_interval_secs)
+    }
+}
+
+impl RetentionConfig {
+    pub fn purge_interval(&self) -> Duration {
+        Duration::from_secs(self.purge_interval_secs)
+    }
+}
+
+impl BucketConfig {
+    fn new(per_second: f64, burst: u32) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_config() -> Config {
+        let mut config = Config::default();
+        config.database.storage = StorageBackend::Memory;
+        config
+    }
+
+    fn reason(config: &Config) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(reason)) => reason,
+            other => panic!("Expected an invalid config, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid_without_postgres() {
+        assert!(memory_config().validate().is_ok());
+        assert!(reason(&Config::default()).starts_with("database.url"));
+    }
+
+    #[test]
+    fn example_config_parses_and_validates() {
+        let mut config: Config = toml::from_str(include_str!("../hnet.example.toml")).unwrap();
+        config.database.url = Some("postgres://localhost/hnet".to_string());
+
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_conflicting_ports() {
+        let mut config = memory_config();
+        config.server.ws_port = Some(config.server.port);
+
+        assert_eq!(
+            reason(&config),
+            "server.ws_port must differ from server.port"
+        );
+    }
+
+    #[test]
+    fn rejects_tls_cert_without_key() {
+        let mut config = memory_config();
+        config.server.tls_cert_path = Some(PathBuf::from("cert.pem"));
+
+        assert_eq!(
+            reason(&config),
+            "server.tls_cert_path and server.tls_key_path must be set together"
+        );
+    }
+
+    #[test]
+    fn rejects_ping_after_idle_timeout() {
+        let mut config = memory_config();
+        config.session.ping_after_secs = config.session.idle_timeout_secs;
+
+        assert_eq!(
+            reason(&config),
+            "session.ping_after_secs must be less than session.idle_timeout_secs"
+        );
+    }
+
+    #[test]
+    fn rejects_default_retention_above_max() {
+        let mut config = memory_config();
+        config.retention.default_days = config.retention.max_days + 1;
+
+        assert_eq!(
+            reason(&config),
+            "retention.default_days must not exceed retention.max_days"
+        );
+    }
+
+    #[test]
+    fn rejects_devices_that_never_expire() {
+        let mut config = memory_config();
+        config.retention.device_expiry_days = 0;
+
+        assert_eq!(
+            reason(&config),
+            "retention.device_expiry_days must be greater than 0"
+        );
+    }
+
+    #[test]
+    fn rejects_pending_bytes_below_message_size() {
+        let mut config = memory_config();
+        config.quota.max_pending_bytes = u64::from(config.quota.max_message_bytes) - 1;
+
+        assert_eq!(
+            reason(&config),
+            "quota.max_pending_bytes must be at least quota.max_message_bytes"
+        );
+    }
+
+    #[test]
+    fn rejects_empty_rate_limit_buckets() {
+        let mut config = memory_config();
+        config.rate_limit.frames.burst = 0;
+        assert_eq!(
+            reason(&config),
+            "rate_limit.frames needs per_second and burst greater than 0"
+        );
+
+        let mut config = memory_config();
+        config.rate_limit.search.per_second = f64::NAN;
+        assert_eq!(
+            reason(&config),
+            "rate_limit.search needs per_second and burst greater than 0"
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("[server]\nprot = 8123").is_err());
+    }
+}
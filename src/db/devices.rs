@@ -0,0 +1,252 @@
+use crate::db::ExpiredMessage;
+use crate::db::pending::lock_recipient;
+use sqlx::{PgPool, Postgres, Transaction};
+
+/// Devices dropped from accounts, and the messages lost with them.
+#[derive(Debug, Default)]
+pub struct RemovedDevices {
+    /// Public key and device id of every removed device.
+    pub devices: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Undelivered messages whose remaining copies were all on the removed devices.
+    pub lost: Vec<ExpiredMessage>,
+}
+
+impl RemovedDevices {
+    pub(super) fn append(&mut self, mut other: RemovedDevices) {
+        self.devices.append(&mut other.devices);
+        self.lost.append(&mut other.lost);
+    }
+}
+
+pub struct UserDevice;
+
+impl UserDevice {
+    /// Messages queued while the account had no device are copied to each of its devices,
+    /// so every device acks its own copy. Past `max_devices` the devices seen least recently
+    /// are removed.
+    pub async fn register(
+        pool: &PgPool,
+        public_key: &[u8],
+        device_id: &[u8],
+        max_devices: i64,
+    ) -> Result<RemovedDevices, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        // Taken before the insert, a message queued concurrently sees either no device or all of them.
+        lock_recipient(&mut tx, public_key).await?;
+
+        sqlx::query(
+            "INSERT INTO user_devices (public_key, device_id)
+             VALUES ($1, $2)
+             ON CONFLICT (public_key, device_id) DO UPDATE SET last_seen_at = NOW()",
+        )
+        .bind(public_key)
+        .bind(device_id)
+        .execute(&mut *tx)
+        .await?;
+
+        let evicted = sqlx::query_scalar::<_, Vec<u8>>(
+            "SELECT device_id FROM user_devices
+             WHERE public_key = $1 AND device_id <> $2
+             ORDER BY last_seen_at DESC, created_at DESC
+             OFFSET $3",
+        )
+        .bind(public_key)
+        .bind(device_id)
+        .bind((max_devices - 1).max(0))
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let removed = remove_devices(&mut tx, public_key, &evicted).await?;
+
+        sqlx::query(
+            "WITH unassigned AS (
+                 DELETE FROM pending_messages
+                 WHERE recipient_pubkey = $1 AND recipient_device_id IS NULL
+                 RETURNING id, recipient_pubkey, group_id, client_message_id, sender_pubkey, sender_enc_pubkey, encrypted_content, created_at
+             )
+             INSERT INTO pending_messages (recipient_pubkey, recipient_device_id, group_id, client_message_id, sender_pubkey, sender_enc_pubkey, encrypted_content, created_at)
+             SELECT u.recipient_pubkey, d.device_id, u.group_id, u.client_message_id, u.sender_pubkey, u.sender_enc_pubkey, u.encrypted_content, u.created_at
+             FROM unassigned u
+             JOIN user_devices d ON d.public_key = u.recipient_pubkey
+             ORDER BY u.id ASC, d.created_at ASC",
+        )
+        .bind(public_key)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(removed)
+    }
+
+    pub async fn touch(
+        pool: &PgPool,
+        public_key: &[u8],
+        device_id: &[u8],
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE user_devices SET last_seen_at = NOW() WHERE public_key = $1 AND device_id = $2",
+        )
+        .bind(public_key)
+        .bind(device_id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove(
+        pool: &PgPool,
+        public_key: &[u8],
+        device_id: &[u8],
+    ) -> Result<RemovedDevices, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        lock_recipient(&mut tx, public_key).await?;
+        let removed = remove_devices(&mut tx, public_key, &[device_id.to_vec()]).await?;
+
+        tx.commit().await?;
+
+        Ok(removed)
+    }
+
+    /// Removes up to `limit` devices not seen for `older_than_days`.
+    pub async fn purge_stale(
+        pool: &PgPool,
+        older_than_days: i32,
+        limit: i64,
+    ) -> Result<RemovedDevices, sqlx::Error> {
+        let stale = sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(
+            "SELECT public_key, device_id FROM user_devices
+             WHERE last_seen_at < NOW() - make_interval(days => $1)
+             ORDER BY last_seen_at ASC
+             LIMIT $2",
+        )
+        .bind(older_than_days)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?;
+
+        let mut by_user: Vec<(Vec<u8>, Vec<Vec<u8>>)> = Vec::new();
+        for (public_key, device_id) in stale {
+            match by_user.iter_mut().find(|(known, _)| *known == public_key) {
+                Some((_, devices)) => devices.push(device_id),
+                None => by_user.push((public_key, vec![device_id])),
+            }
+        }
+
+        let mut removed = RemovedDevices::default();
+
+        for (public_key, device_ids) in by_user {
+            let mut tx = pool.begin().await?;
+
+            lock_recipient(&mut tx, &public_key).await?;
+
+            // The device may have logged in since it was picked.
+            let still_stale = sqlx::query_scalar::<_, Vec<u8>>(
+                "SELECT device_id FROM user_devices
+                 WHERE public_key = $1 AND device_id = ANY($2)
+                   AND last_seen_at < NOW() - make_interval(days => $3)",
+            )
+            .bind(&public_key)
+            .bind(&device_ids)
+            .bind(older_than_days)
+            .fetch_all(&mut *tx)
+            .await?;
+
+            removed.append(remove_devices(&mut tx, &public_key, &still_stale).await?);
+
+            tx.commit().await?;
+        }
+
+        Ok(removed)
+    }
+
+    pub async fn list_for_user(
+        pool: &PgPool,
+        public_key: &[u8],
+    ) -> Result<Vec<Vec<u8>>, sqlx::Error> {
+        let devices = sqlx::query_as::<_, (Vec<u8>,)>(
+            "SELECT device_id FROM user_devices WHERE public_key = $1 ORDER BY created_at ASC",
+        )
+        .bind(public_key)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|(device_id,)| device_id)
+        .collect();
+
+        Ok(devices)
+    }
+}
+
+/// Deletes the devices with their message copies and receipts. The caller holds the recipient lock.
+async fn remove_devices(
+    tx: &mut Transaction<'_, Postgres>,
+    public_key: &[u8],
+    device_ids: &[Vec<u8>],
+) -> Result<RemovedDevices, sqlx::Error> {
+    if device_ids.is_empty() {
+        return Ok(RemovedDevices::default());
+    }
+
+    let removed = sqlx::query_scalar::<_, Vec<u8>>(
+        "DELETE FROM user_devices WHERE public_key = $1 AND device_id = ANY($2) RETURNING device_id",
+    )
+    .bind(public_key)
+    .bind(device_ids)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM pending_receipts WHERE recipient_pubkey = $1 AND recipient_device_id = ANY($2)",
+    )
+    .bind(public_key)
+    .bind(&removed)
+    .execute(&mut **tx)
+    .await?;
+
+    // The statement sees the copies it deletes, so copies on the removed devices are excluded by hand.
+    let lost = sqlx::query_as::<_, (Vec<u8>, i64)>(
+        "WITH dropped AS (
+             DELETE FROM pending_messages
+             WHERE recipient_pubkey = $1 AND recipient_device_id = ANY($2)
+             RETURNING sender_pubkey, client_message_id
+         )
+         SELECT DISTINCT d.sender_pubkey, d.client_message_id
+         FROM dropped d
+         LEFT JOIN sent_messages s
+                ON s.sender_pubkey = d.sender_pubkey
+               AND s.recipient_pubkey = $1
+               AND s.client_message_id = d.client_message_id
+         WHERE NOT COALESCE(s.delivered, FALSE)
+           AND NOT EXISTS (
+               SELECT 1 FROM pending_messages p
+               WHERE p.recipient_pubkey = $1
+                 AND p.sender_pubkey = d.sender_pubkey
+                 AND p.client_message_id = d.client_message_id
+                 AND (p.recipient_device_id IS NULL OR NOT (p.recipient_device_id = ANY($2)))
+           )",
+    )
+    .bind(public_key)
+    .bind(&removed)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(RemovedDevices {
+        devices: removed
+            .into_iter()
+            .map(|device_id| (public_key.to_vec(), device_id))
+            .collect(),
+        lost: lost
+            .into_iter()
+            .map(|(sender_pubkey, client_message_id)| ExpiredMessage {
+                recipient_pubkey: public_key.to_vec(),
+                sender_pubkey,
+                client_message_id,
+                delivered: false,
+            })
+            .collect(),
+    })
+}
@@ -0,0 +1,249 @@
+use sqlx::PgPool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupRole {
+    Admin,
+    Member,
+}
+
+impl GroupRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GroupRole::Admin => "admin",
+            GroupRole::Member => "member",
+        }
+    }
+
+    fn from_str(role: &str) -> Self {
+        match role {
+            "admin" => GroupRole::Admin,
+            _ => GroupRole::Member,
+        }
+    }
+}
+
+pub struct Group;
+
+impl Group {
+    /// Returns None once `creator` has created `max_groups` groups.
+    pub async fn create(
+        pool: &PgPool,
+        name: &str,
+        creator: &[u8],
+        members: &[Vec<u8>],
+        max_groups: i64,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        // Serializes group creation per creator, so concurrent requests cannot pass the cap together.
+        sqlx::query(
+            "SELECT pg_advisory_xact_lock(hashtextextended('groups:' || encode($1, 'hex'), 0))",
+        )
+        .bind(creator)
+        .execute(&mut *tx)
+        .await?;
+
+        let (created,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM groups WHERE created_by = $1")
+                .bind(creator)
+                .fetch_one(&mut *tx)
+                .await?;
+
+        if created >= max_groups {
+            return Ok(None);
+        }
+
+        let (group_id,): (i64,) =
+            sqlx::query_as("INSERT INTO groups (name, created_by) VALUES ($1, $2) RETURNING id")
+                .bind(name)
+                .bind(creator)
+                .fetch_one(&mut *tx)
+                .await?;
+
+        sqlx::query("INSERT INTO group_members (group_id, public_key, role) VALUES ($1, $2, $3)")
+            .bind(group_id)
+            .bind(creator)
+            .bind(GroupRole::Admin.as_str())
+            .execute(&mut *tx)
+            .await?;
+
+        for member in members {
+            sqlx::query(
+                "INSERT INTO group_members (group_id, public_key, role) VALUES ($1, $2, $3)
+                 ON CONFLICT (group_id, public_key) DO NOTHING",
+            )
+            .bind(group_id)
+            .bind(member)
+            .bind(GroupRole::Member.as_str())
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(Some(group_id))
+    }
+
+    pub async fn find_role(
+        pool: &PgPool,
+        group_id: i64,
+        public_key: &[u8],
+    ) -> Result<Option<GroupRole>, sqlx::Error> {
+        let role = sqlx::query_as::<_, (String,)>(
+            "SELECT role FROM group_members WHERE group_id = $1 AND public_key = $2",
+        )
+        .bind(group_id)
+        .bind(public_key)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(role.map(|(role,)| GroupRole::from_str(&role)))
+    }
+
+    pub async fn list_members(pool: &PgPool, group_id: i64) -> Result<Vec<Vec<u8>>, sqlx::Error> {
+        let members = sqlx::query_as::<_, (Vec<u8>,)>(
+            "SELECT public_key FROM group_members WHERE group_id = $1",
+        )
+        .bind(group_id)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|(public_key,)| public_key)
+        .collect();
+
+        Ok(members)
+    }
+
+    pub async fn share_any(
+        pool: &PgPool,
+        first: &[u8],
+        second: &[u8],
+    ) -> Result<bool, sqlx::Error> {
+        let (shared,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS (
+                 SELECT 1
+                 FROM group_members a
+                 JOIN group_members b ON a.group_id = b.group_id
+                 WHERE a.public_key = $1 AND b.public_key = $2
+             )",
+        )
+        .bind(first)
+        .bind(second)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(shared)
+    }
+
+    /// Adds the member unless the group already has `max_members`. Returns false for a full or
+    /// missing group.
+    pub async fn add_member(
+        pool: &PgPool,
+        group_id: i64,
+        public_key: &[u8],
+        max_members: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        // Locking the group row makes concurrent adds count each other's members.
+        let exists = sqlx::query("SELECT id FROM groups WHERE id = $1 FOR UPDATE")
+            .bind(group_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+
+        if !exists {
+            return Ok(false);
+        }
+
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM group_members WHERE group_id = $1")
+                .bind(group_id)
+                .fetch_one(&mut *tx)
+                .await?;
+
+        if count >= max_members {
+            return Ok(false);
+        }
+
+        sqlx::query(
+            "INSERT INTO group_members (group_id, public_key, role) VALUES ($1, $2, $3)
+             ON CONFLICT (group_id, public_key) DO NOTHING",
+        )
+        .bind(group_id)
+        .bind(public_key)
+        .bind(GroupRole::Member.as_str())
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    pub async fn set_role(
+        pool: &PgPool,
+        group_id: i64,
+        public_key: &[u8],
+        role: GroupRole,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE group_members SET role = $3 WHERE group_id = $1 AND public_key = $2",
+        )
+        .bind(group_id)
+        .bind(public_key)
+        .bind(role.as_str())
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Removing the last admin hands the role to the member who joined first, removing the last member drops the group.
+    pub async fn remove_member(
+        pool: &PgPool,
+        group_id: i64,
+        public_key: &[u8],
+    ) -> Result<bool, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        let removed =
+            sqlx::query("DELETE FROM group_members WHERE group_id = $1 AND public_key = $2")
+                .bind(group_id)
+                .bind(public_key)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected()
+                > 0;
+
+        if removed {
+            sqlx::query(
+                "UPDATE group_members SET role = $2
+                 WHERE group_id = $1
+                   AND NOT EXISTS (SELECT 1 FROM group_members WHERE group_id = $1 AND role = $2)
+                   AND public_key = (
+                       SELECT public_key FROM group_members
+                       WHERE group_id = $1
+                       ORDER BY position ASC
+                       LIMIT 1
+                   )",
+            )
+            .bind(group_id)
+            .bind(GroupRole::Admin.as_str())
+            .execute(&mut *tx)
+            .await?;
+
+            sqlx::query(
+                "DELETE FROM groups
+                 WHERE id = $1 AND NOT EXISTS (SELECT 1 FROM group_members WHERE group_id = $1)",
+            )
+            .bind(group_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(removed)
+    }
+}
@@ -0,0 +1,1507 @@
+use crate::db::models::UserProfile;
+use crate::db::storage::{
+    NewPendingMessage, NewReceipt, PendingQuota, Queued, Storage, StorageError, StorageResult,
+};
+use crate::db::{ExpiredMessage, GroupRole, PendingMessage, PendingReceipt, RemovedDevices};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+
+// Same cut-off as the pg_trgm `%` operator.
+const SIMILARITY_THRESHOLD: f32 = 0.3;
+
+struct StoredPending {
+    message: PendingMessage,
+    recipient_device_id: Option<Vec<u8>>,
+}
+
+impl StoredPending {
+    fn is_for(&self, recipient_pubkey: &[u8], recipient_device_id: &[u8]) -> bool {
+        self.message.recipient_pubkey == recipient_pubkey
+            && self.recipient_device_id.as_deref() == Some(recipient_device_id)
+    }
+}
+
+struct StoredReceipt {
+    receipt: PendingReceipt,
+    recipient_pubkey: Vec<u8>,
+    recipient_device_id: Vec<u8>,
+    created_at: DateTime<Utc>,
+}
+
+struct StoredGroup {
+    _name: String,
+    created_by: Vec<u8>,
+    // Kept in join order so the oldest member inherits the admin role.
+    members: Vec<(Vec<u8>, GroupRole)>,
+}
+
+struct StoredDevice {
+    device_id: Vec<u8>,
+    last_seen_at: DateTime<Utc>,
+}
+
+struct SentMessage {
+    sent_at: DateTime<Utc>,
+    delivered: bool,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    users: HashMap<Vec<u8>, UserProfile>,
+    // Kept in registration order.
+    devices: HashMap<Vec<u8>, Vec<StoredDevice>>,
+    pending: BTreeMap<i64, StoredPending>,
+    // recipient -> ids of their rows in `pending`, so lookups for one recipient skip everyone else's
+    pending_by_recipient: HashMap<Vec<u8>, BTreeSet<i64>>,
+    next_pending_id: i64,
+    receipts: BTreeMap<i64, StoredReceipt>,
+    next_receipt_id: i64,
+    sent: HashMap<(Vec<u8>, Vec<u8>, i64), SentMessage>,
+    groups: HashMap<i64, StoredGroup>,
+    next_group_id: i64,
+}
+
+impl MemoryState {
+    fn insert_pending(&mut self, stored: StoredPending) {
+        self.pending_by_recipient
+            .entry(stored.message.recipient_pubkey.clone())
+            .or_default()
+            .insert(stored.message.id);
+        self.pending.insert(stored.message.id, stored);
+    }
+
+    fn remove_pending(&mut self, id: i64) -> Option<StoredPending> {
+        let stored = self.pending.remove(&id)?;
+        let recipient_pubkey = &stored.message.recipient_pubkey;
+
+        if let Some(ids) = self.pending_by_recipient.get_mut(recipient_pubkey) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                self.pending_by_recipient.remove(recipient_pubkey);
+            }
+        }
+
+        Some(stored)
+    }
+
+    /// Rows queued for one recipient, oldest first.
+    fn pending_for(&self, recipient_pubkey: &[u8]) -> impl Iterator<Item = &StoredPending> {
+        self.pending_by_recipient
+            .get(recipient_pubkey)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.pending.get(id))
+    }
+
+    fn queue_for_recipient(
+        &mut self,
+        recipient_pubkey: &[u8],
+        message: &NewPendingMessage<'_>,
+        quota: PendingQuota,
+        created_at: DateTime<Utc>,
+    ) -> Queued {
+        let key = (
+            message.sender_pubkey.to_vec(),
+            recipient_pubkey.to_vec(),
+            message.client_message_id,
+        );
+
+        if self.sent.contains_key(&key) {
+            return Queued::Duplicate;
+        }
+
+        let devices = self.device_ids(recipient_pubkey);
+
+        // Each message counts once, however many devices hold a copy.
+        let mut counted = HashSet::new();
+        let (messages, bytes) = self
+            .pending_for(recipient_pubkey)
+            .filter(|stored| {
+                counted.insert((
+                    &stored.message.sender_pubkey,
+                    stored.message.client_message_id,
+                ))
+            })
+            .fold((0, 0), |(messages, bytes), stored| {
+                (
+                    messages + 1,
+                    bytes + stored.message.encrypted_content.len() as i64,
+                )
+            });
+
+        if messages + 1 > quota.max_messages {
+            return Queued::QueueFull;
+        }
+        if bytes + message.encrypted_content.len() as i64 > quota.max_bytes {
+            return Queued::QueueBytesExceeded;
+        }
+
+        self.sent.insert(
+            key,
+            SentMessage {
+                sent_at: created_at,
+                delivered: false,
+            },
+        );
+
+        let targets: Vec<Option<Vec<u8>>> = if devices.is_empty() {
+            vec![None]
+        } else {
+            devices.into_iter().map(Some).collect()
+        };
+
+        let mut queued = Vec::new();
+
+        for device_id in targets {
+            self.next_pending_id += 1;
+            let id = self.next_pending_id;
+
+            self.insert_pending(StoredPending {
+                message: PendingMessage {
+                    id,
+                    recipient_pubkey: recipient_pubkey.to_vec(),
+                    group_id: message.group_id,
+                    client_message_id: message.client_message_id,
+                    sender_pubkey: message.sender_pubkey.to_vec(),
+                    sender_enc_pubkey: message.sender_enc_pubkey.to_vec(),
+                    encrypted_content: message.encrypted_content.to_vec(),
+                    created_at,
+                },
+                recipient_device_id: device_id.clone(),
+            });
+
+            if let Some(device_id) = device_id {
+                queued.push((device_id, id));
+            }
+        }
+
+        Queued::Devices(queued)
+    }
+
+    fn device_ids(&self, public_key: &[u8]) -> Vec<Vec<u8>> {
+        self.devices
+            .get(public_key)
+            .map(|devices| {
+                devices
+                    .iter()
+                    .map(|device| device.device_id.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Same as the Postgres version: copies and receipts of the devices go with them, and a
+    /// message is lost once no copy of it is left and none was delivered.
+    fn remove_devices(&mut self, public_key: &[u8], device_ids: &[Vec<u8>]) -> RemovedDevices {
+        let Some(devices) = self.devices.get_mut(public_key) else {
+            return RemovedDevices::default();
+        };
+
+        let removed: Vec<Vec<u8>> = device_ids
+            .iter()
+            .filter(|device_id| devices.iter().any(|device| device.device_id == **device_id))
+            .cloned()
+            .collect();
+
+        if removed.is_empty() {
+            return RemovedDevices::default();
+        }
+
+        devices.retain(|device| !removed.contains(&device.device_id));
+        if devices.is_empty() {
+            self.devices.remove(public_key);
+        }
+
+        let on_removed = |pubkey: &[u8], device_id: Option<&Vec<u8>>| {
+            pubkey == public_key && device_id.is_some_and(|device_id| removed.contains(device_id))
+        };
+
+        self.receipts.retain(|_, stored| {
+            !on_removed(&stored.recipient_pubkey, Some(&stored.recipient_device_id))
+        });
+
+        let dropped: Vec<i64> = self
+            .pending_for(public_key)
+            .filter(|stored| {
+                on_removed(
+                    &stored.message.recipient_pubkey,
+                    stored.recipient_device_id.as_ref(),
+                )
+            })
+            .map(|stored| stored.message.id)
+            .collect();
+
+        let mut lost: Vec<ExpiredMessage> = Vec::new();
+
+        for id in dropped {
+            let Some(stored) = self.remove_pending(id) else {
+                continue;
+            };
+            let PendingMessage {
+                sender_pubkey,
+                client_message_id,
+                ..
+            } = stored.message;
+
+            let delivered = self
+                .sent
+                .get(&(
+                    sender_pubkey.clone(),
+                    public_key.to_vec(),
+                    client_message_id,
+                ))
+                .is_some_and(|sent| sent.delivered);
+            let reported = lost.iter().any(|message| {
+                message.sender_pubkey == sender_pubkey
+                    && message.client_message_id == client_message_id
+            });
+
+            if !delivered && !reported {
+                lost.push(ExpiredMessage {
+                    recipient_pubkey: public_key.to_vec(),
+                    sender_pubkey,
+                    client_message_id,
+                    delivered: false,
+                });
+            }
+        }
+
+        // Messages another device still holds a copy of are not lost.
+        lost.retain(|message| {
+            !self.pending_for(public_key).any(|stored| {
+                stored.message.sender_pubkey == message.sender_pubkey
+                    && stored.message.client_message_id == message.client_message_id
+            })
+        });
+
+        RemovedDevices {
+            devices: removed
+                .into_iter()
+                .map(|device_id| (public_key.to_vec(), device_id))
+                .collect(),
+            lost,
+        }
+    }
+
+    fn username_taken(&self, public_key: &[u8], username: Option<&str>) -> bool {
+        username.is_some_and(|username| {
+            self.users.values().any(|user| {
+                user.public_key != public_key && user.username.as_deref() == Some(username)
+            })
+        })
+    }
+}
+
+/// Keeps everything in process memory, nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn find_user(&self, public_key: &[u8]) -> StorageResult<Option<UserProfile>> {
+        Ok(self.state.lock().unwrap().users.get(public_key).cloned())
+    }
+
+    async fn known_users(&self, public_keys: &[Vec<u8>]) -> StorageResult<Vec<Vec<u8>>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(public_keys
+            .iter()
+            .filter(|public_key| state.users.contains_key(*public_key))
+            .cloned()
+            .collect())
+    }
+
+    async fn search_users(
+        &self,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> StorageResult<Vec<UserProfile>> {
+        let state = self.state.lock().unwrap();
+
+        let mut matches: Vec<(bool, bool, f32, &UserProfile)> = state
+            .users
+            .values()
+            .filter_map(|user| {
+                let username = user.username.as_deref().unwrap_or_default().to_lowercase();
+                let mut names = vec![username.as_str()];
+
+                let first_name = user.first_name.to_lowercase();
+                let last_name = user.last_name.as_deref().unwrap_or_default().to_lowercase();
+                if user.discoverable_by_name {
+                    names.push(&first_name);
+                    names.push(&last_name);
+                }
+
+                let prefix = names.iter().any(|name| name.starts_with(query));
+                let score = names
+                    .iter()
+                    .map(|name| similarity(name, query))
+                    .fold(0.0, f32::max);
+
+                (prefix || score >= SIMILARITY_THRESHOLD)
+                    .then(|| (username == query, username.starts_with(query), score, user))
+            })
+            .collect();
+
+        matches.sort_by(|a, b| {
+            b.0.cmp(&a.0)
+                .then(b.1.cmp(&a.1))
+                .then(b.2.total_cmp(&a.2))
+                .then(a.3.public_key.cmp(&b.3.public_key))
+        });
+
+        Ok(matches
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .map(|(_, _, _, user)| user.clone())
+            .collect())
+    }
+
+    async fn create_user(
+        &self,
+        public_key: &[u8],
+        encryption_pubkey: &[u8],
+        first_name: &str,
+        username: Option<&str>,
+        last_name: Option<&str>,
+    ) -> StorageResult<UserProfile> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.users.contains_key(public_key) {
+            return Err(StorageError::Conflict("User already exists"));
+        }
+
+        if state.username_taken(public_key, username) {
+            return Err(StorageError::Conflict("Username already taken"));
+        }
+
+        let now = Utc::now();
+        let profile = UserProfile {
+            public_key: public_key.to_vec(),
+            username: username.map(str::to_string),
+            first_name: first_name.to_string(),
+            last_name: last_name.map(str::to_string),
+            custom_avatar: None,
+            encryption_pubkey: encryption_pubkey.to_vec(),
+            pending_retention_days: None,
+            read_receipts: true,
+            last_seen_at: None,
+            last_seen_visibility: 0,
+            discoverable_by_name: true,
+            created_at: now,
+            updated_at: now,
+        };
+
+        state.users.insert(public_key.to_vec(), profile.clone());
+
+        Ok(profile)
+    }
+
+    async fn update_user(
+        &self,
+        public_key: &[u8],
+        encryption_pubkey: &[u8],
+        first_name: &str,
+        username: Option<&str>,
+        last_name: Option<&str>,
+    ) -> StorageResult<UserProfile> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.username_taken(public_key, username) {
+            return Err(StorageError::Conflict("Username already taken"));
+        }
+
+        let profile = state
+            .users
+            .get_mut(public_key)
+            .ok_or(StorageError::NotFound("User not found"))?;
+
+        profile.encryption_pubkey = encryption_pubkey.to_vec();
+        profile.first_name = first_name.to_string();
+        profile.username = username.map(str::to_string);
+        profile.last_name = last_name.map(str::to_string);
+        profile.updated_at = Utc::now();
+
+        Ok(profile.clone())
+    }
+
+    async fn set_pending_retention(
+        &self,
+        public_key: &[u8],
+        days: Option<i32>,
+    ) -> StorageResult<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.users.get_mut(public_key) {
+            Some(profile) => {
+                profile.pending_retention_days = days;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn set_read_receipts(&self, public_key: &[u8], enabled: bool) -> StorageResult<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.users.get_mut(public_key) {
+            Some(profile) => {
+                profile.read_receipts = enabled;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn set_discoverable_by_name(
+        &self,
+        public_key: &[u8],
+        discoverable: bool,
+    ) -> StorageResult<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.users.get_mut(public_key) {
+            Some(profile) => {
+                profile.discoverable_by_name = discoverable;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn set_last_seen(&self, public_key: &[u8], at: DateTime<Utc>) -> StorageResult<()> {
+        if let Some(profile) = self.state.lock().unwrap().users.get_mut(public_key) {
+            profile.last_seen_at = Some(at);
+        }
+
+        Ok(())
+    }
+
+    async fn set_last_seen_visibility(
+        &self,
+        public_key: &[u8],
+        visibility: i16,
+    ) -> StorageResult<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.users.get_mut(public_key) {
+            Some(profile) => {
+                profile.last_seen_visibility = visibility;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn register_device(
+        &self,
+        public_key: &[u8],
+        device_id: &[u8],
+        max_devices: i64,
+    ) -> StorageResult<RemovedDevices> {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+        let devices = state.devices.entry(public_key.to_vec()).or_default();
+
+        match devices
+            .iter_mut()
+            .find(|known| known.device_id == device_id)
+        {
+            Some(known) => known.last_seen_at = now,
+            None => devices.push(StoredDevice {
+                device_id: device_id.to_vec(),
+                last_seen_at: now,
+            }),
+        }
+
+        // Most recently seen first, later registrations win a tie.
+        let mut others: Vec<(usize, &StoredDevice)> = devices
+            .iter()
+            .enumerate()
+            .filter(|(_, known)| known.device_id != device_id)
+            .collect();
+        others.sort_by(|(a_index, a), (b_index, b)| {
+            b.last_seen_at
+                .cmp(&a.last_seen_at)
+                .then(b_index.cmp(a_index))
+        });
+        let evicted: Vec<Vec<u8>> = others
+            .into_iter()
+            .skip((max_devices - 1).max(0) as usize)
+            .map(|(_, known)| known.device_id.clone())
+            .collect();
+
+        let removed = state.remove_devices(public_key, &evicted);
+        let devices = state.device_ids(public_key);
+
+        // Messages queued before the account had a device get a copy per device, as in Postgres.
+        let unassigned: Vec<i64> = state
+            .pending_for(public_key)
+            .filter(|stored| stored.recipient_device_id.is_none())
+            .map(|stored| stored.message.id)
+            .collect();
+
+        for id in unassigned {
+            let Some(stored) = state.remove_pending(id) else {
+                continue;
+            };
+
+            for device_id in &devices {
+                state.next_pending_id += 1;
+                let id = state.next_pending_id;
+
+                state.insert_pending(StoredPending {
+                    message: PendingMessage {
+                        id,
+                        ..stored.message.clone()
+                    },
+                    recipient_device_id: Some(device_id.clone()),
+                });
+            }
+        }
+
+        Ok(removed)
+    }
+
+    async fn touch_device(&self, public_key: &[u8], device_id: &[u8]) -> StorageResult<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(device) = state.devices.get_mut(public_key).and_then(|devices| {
+            devices
+                .iter_mut()
+                .find(|known| known.device_id == device_id)
+        }) {
+            device.last_seen_at = Utc::now();
+        }
+
+        Ok(())
+    }
+
+    async fn remove_device(
+        &self,
+        public_key: &[u8],
+        device_id: &[u8],
+    ) -> StorageResult<RemovedDevices> {
+        let mut state = self.state.lock().unwrap();
+
+        Ok(state.remove_devices(public_key, &[device_id.to_vec()]))
+    }
+
+    async fn purge_stale_devices(
+        &self,
+        older_than_days: i32,
+        limit: i64,
+    ) -> StorageResult<RemovedDevices> {
+        let mut state = self.state.lock().unwrap();
+        let cutoff = Utc::now() - Duration::days(older_than_days.into());
+
+        let mut stale: Vec<(DateTime<Utc>, Vec<u8>, Vec<u8>)> = state
+            .devices
+            .iter()
+            .flat_map(|(public_key, devices)| {
+                devices
+                    .iter()
+                    .filter(|device| device.last_seen_at < cutoff)
+                    .map(|device| {
+                        (
+                            device.last_seen_at,
+                            public_key.clone(),
+                            device.device_id.clone(),
+                        )
+                    })
+            })
+            .collect();
+        stale.sort();
+        stale.truncate(limit.max(0) as usize);
+
+        let mut removed = RemovedDevices::default();
+
+        for (_, public_key, device_id) in stale {
+            removed.append(state.remove_devices(&public_key, &[device_id]));
+        }
+
+        Ok(removed)
+    }
+
+    async fn list_devices(&self, public_key: &[u8]) -> StorageResult<Vec<Vec<u8>>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.device_ids(public_key))
+    }
+
+    async fn queue_pending(
+        &self,
+        recipients: &[Vec<u8>],
+        message: NewPendingMessage<'_>,
+        quota: PendingQuota,
+    ) -> StorageResult<Vec<Queued>> {
+        let mut state = self.state.lock().unwrap();
+        let created_at = Utc::now();
+
+        Ok(recipients
+            .iter()
+            .map(|recipient_pubkey| {
+                state.queue_for_recipient(recipient_pubkey, &message, quota, created_at)
+            })
+            .collect())
+    }
+
+    async fn count_pending(&self) -> StorageResult<i64> {
+        Ok(self.state.lock().unwrap().pending.len() as i64)
+    }
+
+    async fn pending_for_device(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+    ) -> StorageResult<Vec<PendingMessage>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .pending_for(recipient_pubkey)
+            .filter(|stored| stored.is_for(recipient_pubkey, recipient_device_id))
+            .map(|stored| stored.message.clone())
+            .collect())
+    }
+
+    async fn acknowledge_pending(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+        id: i64,
+    ) -> StorageResult<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        let matches = state
+            .pending
+            .get(&id)
+            .is_some_and(|stored| stored.is_for(recipient_pubkey, recipient_device_id));
+
+        if let Some(stored) = matches.then(|| state.remove_pending(id)).flatten() {
+            let key = (
+                stored.message.sender_pubkey,
+                stored.message.recipient_pubkey,
+                stored.message.client_message_id,
+            );
+            if let Some(sent) = state.sent.get_mut(&key) {
+                sent.delivered = true;
+            }
+        }
+
+        Ok(matches)
+    }
+
+    async fn purge_expired_pending(
+        &self,
+        default_retention_days: i32,
+        limit: i64,
+    ) -> StorageResult<Vec<ExpiredMessage>> {
+        let mut state = self.state.lock().unwrap();
+        let now = Utc::now();
+
+        let expired_ids: Vec<i64> = state
+            .pending
+            .values()
+            .filter(|stored| {
+                let retention_days = state
+                    .users
+                    .get(&stored.message.recipient_pubkey)
+                    .and_then(|user| user.pending_retention_days)
+                    .unwrap_or(default_retention_days);
+
+                stored.message.created_at < now - Duration::days(retention_days.into())
+            })
+            .map(|stored| stored.message.id)
+            .take(limit.max(0) as usize)
+            .collect();
+
+        let removed: Vec<StoredPending> = expired_ids
+            .into_iter()
+            .filter_map(|id| state.remove_pending(id))
+            .collect();
+
+        Ok(removed
+            .into_iter()
+            .map(|stored| {
+                let key = (
+                    stored.message.sender_pubkey,
+                    stored.message.recipient_pubkey,
+                    stored.message.client_message_id,
+                );
+                let delivered = state.sent.get(&key).is_some_and(|sent| sent.delivered);
+                let (sender_pubkey, recipient_pubkey, client_message_id) = key;
+
+                ExpiredMessage {
+                    recipient_pubkey,
+                    sender_pubkey,
+                    client_message_id,
+                    delivered,
+                }
+            })
+            .collect())
+    }
+
+    async fn purge_sent_messages(&self, older_than_days: i32) -> StorageResult<u64> {
+        let mut state = self.state.lock().unwrap();
+        let cutoff = Utc::now() - Duration::days(older_than_days.into());
+        let before = state.sent.len();
+
+        state.sent.retain(|_, sent| sent.sent_at >= cutoff);
+
+        Ok((before - state.sent.len()) as u64)
+    }
+
+    async fn sent_message_ids(
+        &self,
+        sender_pubkey: &[u8],
+        recipient_pubkey: &[u8],
+        client_message_ids: &[i64],
+    ) -> StorageResult<Vec<i64>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(client_message_ids
+            .iter()
+            .copied()
+            .filter(|&id| {
+                state
+                    .sent
+                    .contains_key(&(sender_pubkey.to_vec(), recipient_pubkey.to_vec(), id))
+            })
+            .collect())
+    }
+
+    async fn save_receipt(
+        &self,
+        receipt: NewReceipt<'_>,
+        max_pending: i64,
+    ) -> StorageResult<Option<i64>> {
+        let mut state = self.state.lock().unwrap();
+
+        let pending = state
+            .receipts
+            .values()
+            .filter(|stored| stored.recipient_pubkey == receipt.recipient_pubkey)
+            .count() as i64;
+
+        if pending >= max_pending {
+            return Ok(None);
+        }
+
+        state.next_receipt_id += 1;
+        let id = state.next_receipt_id;
+
+        state.receipts.insert(
+            id,
+            StoredReceipt {
+                receipt: PendingReceipt {
+                    id,
+                    reader_pubkey: receipt.reader_pubkey.to_vec(),
+                    client_message_ids: receipt.client_message_ids.to_vec(),
+                    kind: receipt.kind,
+                },
+                recipient_pubkey: receipt.recipient_pubkey.to_vec(),
+                recipient_device_id: receipt.recipient_device_id.to_vec(),
+                created_at: Utc::now(),
+            },
+        );
+
+        Ok(Some(id))
+    }
+
+    async fn pending_receipts_for_device(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+    ) -> StorageResult<Vec<PendingReceipt>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .receipts
+            .values()
+            .filter(|stored| {
+                stored.recipient_pubkey == recipient_pubkey
+                    && stored.recipient_device_id == recipient_device_id
+            })
+            .map(|stored| stored.receipt.clone())
+            .collect())
+    }
+
+    async fn acknowledge_receipt(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+        id: i64,
+    ) -> StorageResult<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        let matches = state.receipts.get(&id).is_some_and(|stored| {
+            stored.recipient_pubkey == recipient_pubkey
+                && stored.recipient_device_id == recipient_device_id
+        });
+
+        if matches {
+            state.receipts.remove(&id);
+        }
+
+        Ok(matches)
+    }
+
+    async fn purge_expired_receipts(&self, retention_days: i32) -> StorageResult<u64> {
+        let mut state = self.state.lock().unwrap();
+        let cutoff = Utc::now() - Duration::days(retention_days.into());
+        let before = state.receipts.len();
+
+        state
+            .receipts
+            .retain(|_, stored| stored.created_at >= cutoff);
+
+        Ok((before - state.receipts.len()) as u64)
+    }
+
+    async fn create_group(
+        &self,
+        name: &str,
+        creator: &[u8],
+        members: &[Vec<u8>],
+        max_groups: i64,
+    ) -> StorageResult<Option<i64>> {
+        let mut state = self.state.lock().unwrap();
+
+        let created = state
+            .groups
+            .values()
+            .filter(|group| group.created_by == creator)
+            .count() as i64;
+
+        if created >= max_groups {
+            return Ok(None);
+        }
+
+        state.next_group_id += 1;
+        let group_id = state.next_group_id;
+
+        let mut group = StoredGroup {
+            _name: name.to_string(),
+            created_by: creator.to_vec(),
+            members: vec![(creator.to_vec(), GroupRole::Admin)],
+        };
+
+        for member in members {
+            if !group.members.iter().any(|(known, _)| known == member) {
+                group.members.push((member.clone(), GroupRole::Member));
+            }
+        }
+
+        state.groups.insert(group_id, group);
+
+        Ok(Some(group_id))
+    }
+
+    async fn group_role(
+        &self,
+        group_id: i64,
+        public_key: &[u8],
+    ) -> StorageResult<Option<GroupRole>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.groups.get(&group_id).and_then(|group| {
+            group
+                .members
+                .iter()
+                .find(|(member, _)| member == public_key)
+                .map(|(_, role)| *role)
+        }))
+    }
+
+    async fn group_members(&self, group_id: i64) -> StorageResult<Vec<Vec<u8>>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .groups
+            .get(&group_id)
+            .map(|group| {
+                group
+                    .members
+                    .iter()
+                    .map(|(member, _)| member.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn share_group(&self, first: &[u8], second: &[u8]) -> StorageResult<bool> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.groups.values().any(|group| {
+            let is_member = |key: &[u8]| group.members.iter().any(|(member, _)| member == key);
+            is_member(first) && is_member(second)
+        }))
+    }
+
+    async fn add_group_member(
+        &self,
+        group_id: i64,
+        public_key: &[u8],
+        max_members: i64,
+    ) -> StorageResult<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        let Some(group) = state.groups.get_mut(&group_id) else {
+            return Ok(false);
+        };
+
+        if group.members.len() as i64 >= max_members {
+            return Ok(false);
+        }
+
+        if !group.members.iter().any(|(member, _)| member == public_key) {
+            group.members.push((public_key.to_vec(), GroupRole::Member));
+        }
+
+        Ok(true)
+    }
+
+    async fn set_group_role(
+        &self,
+        group_id: i64,
+        public_key: &[u8],
+        role: GroupRole,
+    ) -> StorageResult<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        let member = state.groups.get_mut(&group_id).and_then(|group| {
+            group
+                .members
+                .iter_mut()
+                .find(|(member, _)| member == public_key)
+        });
+
+        match member {
+            Some((_, member_role)) => {
+                *member_role = role;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn remove_group_member(&self, group_id: i64, public_key: &[u8]) -> StorageResult<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        let Some(group) = state.groups.get_mut(&group_id) else {
+            return Ok(false);
+        };
+
+        let before = group.members.len();
+        group.members.retain(|(member, _)| member != public_key);
+        let removed = group.members.len() < before;
+
+        if !group
+            .members
+            .iter()
+            .any(|(_, role)| *role == GroupRole::Admin)
+            && let Some((_, role)) = group.members.first_mut()
+        {
+            *role = GroupRole::Admin;
+        }
+
+        if group.members.is_empty() {
+            state.groups.remove(&group_id);
+        }
+
+        Ok(removed)
+    }
+}
+
+// Mirrors pg_trgm: each word is padded with two spaces in front and one behind.
+fn trigrams(value: &str) -> HashSet<[char; 3]> {
+    let mut trigrams = HashSet::new();
+
+    for word in value.split(|c: char| !c.is_alphanumeric()) {
+        if word.is_empty() {
+            continue;
+        }
+
+        let padded: Vec<char> = "  ".chars().chain(word.chars()).chain([' ']).collect();
+        for window in padded.windows(3) {
+            trigrams.insert([window[0], window[1], window[2]]);
+        }
+    }
+
+    trigrams
+}
+
+fn similarity(first: &str, second: &str) -> f32 {
+    let first = trigrams(first);
+    let second = trigrams(second);
+    let union = first.union(&second).count();
+
+    if union == 0 {
+        return 0.0;
+    }
+
+    first.intersection(&second).count() as f32 / union as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENDER: &[u8] = b"sender";
+    const RECIPIENT: &[u8] = b"recipient";
+
+    const QUOTA: PendingQuota = PendingQuota {
+        max_messages: 100,
+        max_bytes: 1024,
+    };
+
+    fn message(client_message_id: i64, content: &[u8]) -> NewPendingMessage<'_> {
+        NewPendingMessage {
+            group_id: None,
+            client_message_id,
+            sender_pubkey: SENDER,
+            sender_enc_pubkey: b"sender-enc",
+            encrypted_content: content,
+        }
+    }
+
+    async fn queue_one(
+        storage: &MemoryStorage,
+        recipient: &[u8],
+        client_message_id: i64,
+        content: &[u8],
+        quota: PendingQuota,
+    ) -> Queued {
+        storage
+            .queue_pending(
+                &[recipient.to_vec()],
+                message(client_message_id, content),
+                quota,
+            )
+            .await
+            .unwrap()
+            .remove(0)
+    }
+
+    async fn register(
+        storage: &MemoryStorage,
+        public_key: &[u8],
+        device_id: &[u8],
+    ) -> RemovedDevices {
+        storage
+            .register_device(public_key, device_id, 3)
+            .await
+            .unwrap()
+    }
+
+    fn copies(queued: Queued) -> Vec<(Vec<u8>, i64)> {
+        match queued {
+            Queued::Devices(copies) => copies,
+            other => panic!("Expected the message to be queued, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn ack_removes_only_the_devices_copy() {
+        let storage = MemoryStorage::new();
+        register(&storage, RECIPIENT, b"phone").await;
+        register(&storage, RECIPIENT, b"laptop").await;
+
+        let copies = copies(queue_one(&storage, RECIPIENT, 1, b"hello", QUOTA).await);
+        assert_eq!(copies.len(), 2);
+
+        let (_, phone_copy) = copies
+            .iter()
+            .find(|(device, _)| device == b"phone")
+            .unwrap();
+        let (_, laptop_copy) = copies
+            .iter()
+            .find(|(device, _)| device == b"laptop")
+            .unwrap();
+
+        // Another device cannot ack a copy that is not its own.
+        assert!(
+            !storage
+                .acknowledge_pending(RECIPIENT, b"phone", *laptop_copy)
+                .await
+                .unwrap()
+        );
+
+        assert!(
+            storage
+                .acknowledge_pending(RECIPIENT, b"phone", *phone_copy)
+                .await
+                .unwrap()
+        );
+        assert!(
+            storage
+                .pending_for_device(RECIPIENT, b"phone")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        assert_eq!(
+            storage
+                .pending_for_device(RECIPIENT, b"laptop")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn first_device_claims_messages_queued_before_it() {
+        let storage = MemoryStorage::new();
+
+        assert!(copies(queue_one(&storage, RECIPIENT, 1, b"hello", QUOTA).await).is_empty());
+        assert!(
+            storage
+                .pending_for_device(RECIPIENT, b"phone")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        register(&storage, RECIPIENT, b"phone").await;
+
+        let pending = storage
+            .pending_for_device(RECIPIENT, b"phone")
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].client_message_id, 1);
+        assert_eq!(storage.count_pending().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn quota_counts_a_message_once_for_all_devices() {
+        let storage = MemoryStorage::new();
+        register(&storage, RECIPIENT, b"phone").await;
+        register(&storage, RECIPIENT, b"laptop").await;
+
+        let quota = PendingQuota {
+            max_messages: 2,
+            max_bytes: 10,
+        };
+
+        assert_eq!(
+            copies(queue_one(&storage, RECIPIENT, 1, b"12345", quota).await).len(),
+            2
+        );
+        assert_eq!(
+            queue_one(&storage, RECIPIENT, 2, b"123456", quota).await,
+            Queued::QueueBytesExceeded
+        );
+        assert_eq!(
+            copies(queue_one(&storage, RECIPIENT, 3, b"12345", quota).await).len(),
+            2
+        );
+        assert_eq!(
+            queue_one(&storage, RECIPIENT, 4, b"1", quota).await,
+            Queued::QueueFull
+        );
+
+        // The quota is per recipient, others still have room.
+        assert_eq!(
+            copies(queue_one(&storage, b"someone-else", 5, b"hello", quota).await).len(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn resent_message_is_not_queued_again() {
+        let storage = MemoryStorage::new();
+        register(&storage, RECIPIENT, b"phone").await;
+
+        copies(queue_one(&storage, RECIPIENT, 1, b"hello", QUOTA).await);
+        assert_eq!(
+            queue_one(&storage, RECIPIENT, 1, b"hello", QUOTA).await,
+            Queued::Duplicate
+        );
+        assert_eq!(storage.count_pending().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn device_past_the_cap_replaces_the_least_recently_seen() {
+        let storage = MemoryStorage::new();
+        register(&storage, RECIPIENT, b"phone").await;
+        register(&storage, RECIPIENT, b"laptop").await;
+        register(&storage, RECIPIENT, b"tablet").await;
+
+        copies(queue_one(&storage, RECIPIENT, 1, b"hello", QUOTA).await);
+
+        // Logging in again makes the phone the most recently seen.
+        storage
+            .state
+            .lock()
+            .unwrap()
+            .devices
+            .get_mut(RECIPIENT)
+            .unwrap()[0]
+            .last_seen_at = Utc::now() + Duration::seconds(1);
+
+        let removed = register(&storage, RECIPIENT, b"watch").await;
+        assert_eq!(
+            removed.devices,
+            vec![(RECIPIENT.to_vec(), b"laptop".to_vec())]
+        );
+        // The other devices still hold a copy, nothing is lost.
+        assert!(removed.lost.is_empty());
+
+        assert_eq!(
+            storage.list_devices(RECIPIENT).await.unwrap(),
+            vec![b"phone".to_vec(), b"tablet".to_vec(), b"watch".to_vec()]
+        );
+        assert!(
+            storage
+                .pending_for_device(RECIPIENT, b"laptop")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn removing_the_last_device_with_a_copy_loses_the_message() {
+        let storage = MemoryStorage::new();
+        register(&storage, RECIPIENT, b"phone").await;
+        register(&storage, RECIPIENT, b"laptop").await;
+
+        let first = copies(queue_one(&storage, RECIPIENT, 1, b"hello", QUOTA).await);
+        copies(queue_one(&storage, RECIPIENT, 2, b"hello", QUOTA).await);
+
+        // The laptop got message 1, only message 2 is still undelivered everywhere.
+        let (laptop, laptop_copy) = first
+            .iter()
+            .find(|(device, _)| device == b"laptop")
+            .unwrap();
+        storage
+            .acknowledge_pending(RECIPIENT, laptop, *laptop_copy)
+            .await
+            .unwrap();
+
+        let removed = storage.remove_device(RECIPIENT, b"laptop").await.unwrap();
+        assert_eq!(removed.devices.len(), 1);
+        assert!(removed.lost.is_empty());
+
+        let removed = storage.remove_device(RECIPIENT, b"phone").await.unwrap();
+        assert_eq!(removed.lost.len(), 1);
+        assert_eq!(removed.lost[0].client_message_id, 2);
+        assert_eq!(removed.lost[0].sender_pubkey, SENDER);
+
+        assert_eq!(storage.count_pending().await.unwrap(), 0);
+        assert!(
+            storage
+                .remove_device(RECIPIENT, b"phone")
+                .await
+                .unwrap()
+                .devices
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn purges_devices_not_seen_since_the_cut_off() {
+        let storage = MemoryStorage::new();
+        register(&storage, RECIPIENT, b"phone").await;
+        register(&storage, RECIPIENT, b"laptop").await;
+
+        storage
+            .state
+            .lock()
+            .unwrap()
+            .devices
+            .get_mut(RECIPIENT)
+            .unwrap()[0]
+            .last_seen_at = Utc::now() - Duration::days(91);
+
+        let removed = storage.purge_stale_devices(90, 100).await.unwrap();
+        assert_eq!(
+            removed.devices,
+            vec![(RECIPIENT.to_vec(), b"phone".to_vec())]
+        );
+        assert_eq!(
+            storage.list_devices(RECIPIENT).await.unwrap(),
+            vec![b"laptop".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn purge_honours_the_recipients_retention() {
+        let storage = MemoryStorage::new();
+        storage
+            .create_user(RECIPIENT, b"enc", "Recipient", None, None)
+            .await
+            .unwrap();
+        storage
+            .set_pending_retention(RECIPIENT, Some(2))
+            .await
+            .unwrap();
+        register(&storage, RECIPIENT, b"phone").await;
+        register(&storage, b"other", b"phone").await;
+
+        copies(queue_one(&storage, RECIPIENT, 1, b"hello", QUOTA).await);
+        copies(queue_one(&storage, b"other", 2, b"hello", QUOTA).await);
+
+        for stored in storage.state.lock().unwrap().pending.values_mut() {
+            stored.message.created_at = Utc::now() - Duration::days(3);
+        }
+
+        // The recipient keeps messages for two days, everyone else for the default of seven.
+        let expired = storage.purge_expired_pending(7, 100).await.unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].recipient_pubkey, RECIPIENT);
+        assert_eq!(expired[0].sender_pubkey, SENDER);
+        assert_eq!(expired[0].client_message_id, 1);
+
+        assert_eq!(storage.count_pending().await.unwrap(), 1);
+        assert!(
+            storage
+                .purge_expired_pending(7, 100)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_copies_of_an_acked_message_are_marked_delivered() {
+        let storage = MemoryStorage::new();
+        register(&storage, RECIPIENT, b"phone").await;
+        register(&storage, RECIPIENT, b"laptop").await;
+
+        let first = copies(queue_one(&storage, RECIPIENT, 1, b"hello", QUOTA).await);
+        copies(queue_one(&storage, RECIPIENT, 2, b"hello", QUOTA).await);
+
+        let (phone, phone_copy) = &first[0];
+        assert!(
+            storage
+                .acknowledge_pending(RECIPIENT, phone, *phone_copy)
+                .await
+                .unwrap()
+        );
+
+        for stored in storage.state.lock().unwrap().pending.values_mut() {
+            stored.message.created_at = Utc::now() - Duration::days(8);
+        }
+
+        let expired = storage.purge_expired_pending(7, 100).await.unwrap();
+        assert_eq!(expired.len(), 3);
+        for message in expired {
+            assert_eq!(message.delivered, message.client_message_id == 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn search_ranks_exact_then_prefix_usernames_first() {
+        let storage = MemoryStorage::new();
+        // Keys chosen so the public key tie-break alone would order them the other way.
+        storage
+            .create_user(&[3], b"enc", "Zed", Some("alice"), None)
+            .await
+            .unwrap();
+        storage
+            .create_user(&[2], b"enc", "Bob", Some("alicent"), None)
+            .await
+            .unwrap();
+        storage
+            .create_user(&[1], b"enc", "Alice", None, None)
+            .await
+            .unwrap();
+        storage
+            .create_user(&[4], b"enc", "Carol", Some("carol"), None)
+            .await
+            .unwrap();
+
+        let keys = |users: Vec<UserProfile>| -> Vec<Vec<u8>> {
+            users.into_iter().map(|user| user.public_key).collect()
+        };
+
+        let found = storage.search_users("alice", 10, 0).await.unwrap();
+        assert_eq!(keys(found), vec![vec![3], vec![2], vec![1]]);
+
+        let page = storage.search_users("alice", 1, 1).await.unwrap();
+        assert_eq!(keys(page), vec![vec![2]]);
+
+        // Names only match for users who allow it.
+        storage.set_discoverable_by_name(&[1], false).await.unwrap();
+        let found = storage.search_users("alice", 10, 0).await.unwrap();
+        assert_eq!(keys(found), vec![vec![3], vec![2]]);
+    }
+
+    #[tokio::test]
+    async fn group_creation_is_capped_per_creator() {
+        let storage = MemoryStorage::new();
+
+        assert!(
+            storage
+                .create_group("one", SENDER, &[], 1)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            storage
+                .create_group("two", SENDER, &[], 1)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            storage
+                .create_group("two", RECIPIENT, &[], 1)
+                .await
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_role_passes_to_the_first_member_to_join() {
+        let storage = MemoryStorage::new();
+        let members = [b"first".to_vec(), b"second".to_vec()];
+        let group_id = storage
+            .create_group("group", SENDER, &members, 10)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(storage.remove_group_member(group_id, SENDER).await.unwrap());
+        assert_eq!(
+            storage.group_role(group_id, b"first").await.unwrap(),
+            Some(GroupRole::Admin)
+        );
+        assert_eq!(
+            storage.group_role(group_id, b"second").await.unwrap(),
+            Some(GroupRole::Member)
+        );
+    }
+
+    #[test]
+    fn similarity_matches_pg_trgm() {
+        assert_eq!(similarity("alice", "alice"), 1.0);
+        assert_eq!(similarity("alice", "xyz"), 0.0);
+        assert_eq!(similarity("", ""), 0.0);
+
+        // 4 shared trigrams of 9, pg_trgm gives similarity('alice', 'alicia') = 0.444444.
+        assert_eq!(similarity("alice", "alicia"), 4.0 / 9.0);
+        // Words are compared separately, so order does not matter.
+        assert_eq!(similarity("ada lovelace", "lovelace ada"), 1.0);
+        assert!(similarity("alice", "alicia") >= SIMILARITY_THRESHOLD);
+        assert!(similarity("alice", "bob") < SIMILARITY_THRESHOLD);
+    }
+}
@@ -1,13 +1,40 @@
+mod devices;
+mod groups;
+mod memory;
 pub mod models;
 mod pending;
+mod postgres;
+mod receipts;
+mod storage;
 
-pub use pending::PendingMessage;
+pub use devices::{RemovedDevices, UserDevice};
+pub use groups::{Group, GroupRole};
+pub use memory::MemoryStorage;
+pub use pending::{ExpiredMessage, PendingMessage};
+pub use postgres::PgStorage;
+pub use receipts::PendingReceipt;
+pub use storage::{NewPendingMessage, NewReceipt, PendingQuota, Queued, Storage, StorageError};
 use sqlx::PgPool;
+use sqlx::migrate::{MigrateError, Migrator};
 use sqlx::postgres::PgPoolOptions;
 
-pub async fn create_pool(database_url: &str) -> Result<PgPool, sqlx::Error> {
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+pub async fn create_pool(database_url: &str, max_connections: u32) -> Result<PgPool, sqlx::Error> {
     PgPoolOptions::new()
-        .max_connections(150)
+        .max_connections(max_connections)
         .connect(database_url)
         .await
 }
+
+pub async fn run_migrations(pool: &PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    match MIGRATOR.run(pool).await {
+        Ok(()) => Ok(()),
+        Err(MigrateError::VersionMissing(version)) => Err(format!(
+            "Database has migration {} applied which this binary does not know, refusing to start",
+            version
+        )
+        .into()),
+        Err(e) => Err(e.into()),
+    }
+}
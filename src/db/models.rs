@@ -9,6 +9,11 @@ pub struct UserProfile {
     pub last_name: Option<String>,
     pub custom_avatar: Option<Vec<u8>>,
     pub encryption_pubkey: Vec<u8>,
+    pub pending_retention_days: Option<i32>,
+    pub read_receipts: bool,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub last_seen_visibility: i16,
+    pub discoverable_by_name: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -24,6 +29,63 @@ impl UserProfile {
             .await
     }
 
+    pub async fn existing_keys(
+        pool: &sqlx::PgPool,
+        public_keys: &[Vec<u8>],
+    ) -> Result<Vec<Vec<u8>>, sqlx::Error> {
+        let keys = sqlx::query_as::<_, (Vec<u8>,)>(
+            "SELECT public_key FROM users WHERE public_key = ANY($1)",
+        )
+        .bind(public_keys)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|(public_key,)| public_key)
+        .collect();
+
+        Ok(keys)
+    }
+
+    /// Prefix and trigram match on username, and on names for users who allow it.
+    /// `query` must already be lowercase.
+    pub async fn search(
+        pool: &sqlx::PgPool,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        let prefix = format!("{}%", escape_like(query));
+
+        sqlx::query_as::<_, UserProfile>(
+            r"SELECT * FROM users
+             WHERE lower(username) LIKE $1 ESCAPE '\'
+                OR lower(username) % $2
+                OR (discoverable_by_name AND (
+                       lower(first_name) LIKE $1 ESCAPE '\'
+                    OR lower(last_name) LIKE $1 ESCAPE '\'
+                    OR lower(first_name) % $2
+                    OR lower(last_name) % $2
+                ))
+             ORDER BY coalesce(lower(username) = $2, false) DESC,
+                      coalesce(lower(username) LIKE $1 ESCAPE '\', false) DESC,
+                      GREATEST(
+                          similarity(lower(coalesce(username, '')), $2),
+                          CASE WHEN discoverable_by_name THEN GREATEST(
+                              similarity(lower(first_name), $2),
+                              similarity(lower(coalesce(last_name, '')), $2)
+                          ) ELSE 0 END
+                      ) DESC,
+                      public_key
+             LIMIT $3 OFFSET $4",
+        )
+        .bind(prefix)
+        .bind(query)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await
+    }
+
     pub async fn create(
         pool: &sqlx::PgPool,
         public_key: &[u8],
@@ -68,4 +130,84 @@ impl UserProfile {
         .fetch_one(pool)
         .await
     }
+
+    pub async fn set_pending_retention(
+        pool: &sqlx::PgPool,
+        public_key: &[u8],
+        days: Option<i32>,
+    ) -> Result<bool, sqlx::Error> {
+        let result =
+            sqlx::query("UPDATE users SET pending_retention_days = $2 WHERE public_key = $1")
+                .bind(public_key)
+                .bind(days)
+                .execute(pool)
+                .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn set_read_receipts(
+        pool: &sqlx::PgPool,
+        public_key: &[u8],
+        enabled: bool,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE users SET read_receipts = $2 WHERE public_key = $1")
+            .bind(public_key)
+            .bind(enabled)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn set_last_seen(
+        pool: &sqlx::PgPool,
+        public_key: &[u8],
+        at: DateTime<Utc>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE users SET last_seen_at = $2 WHERE public_key = $1")
+            .bind(public_key)
+            .bind(at)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_last_seen_visibility(
+        pool: &sqlx::PgPool,
+        public_key: &[u8],
+        visibility: i16,
+    ) -> Result<bool, sqlx::Error> {
+        let result =
+            sqlx::query("UPDATE users SET last_seen_visibility = $2 WHERE public_key = $1")
+                .bind(public_key)
+                .bind(visibility)
+                .execute(pool)
+                .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn set_discoverable_by_name(
+        pool: &sqlx::PgPool,
+        public_key: &[u8],
+        discoverable: bool,
+    ) -> Result<bool, sqlx::Error> {
+        let result =
+            sqlx::query("UPDATE users SET discoverable_by_name = $2 WHERE public_key = $1")
+                .bind(public_key)
+                .bind(discoverable)
+                .execute(pool)
+                .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
 }
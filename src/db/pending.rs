@@ -1,71 +1,302 @@
+use crate::db::storage::{NewPendingMessage, PendingQuota, Queued};
 use chrono::{DateTime, Utc};
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
+
+#[derive(Debug, Clone)]
+pub struct ExpiredMessage {
+    pub recipient_pubkey: Vec<u8>,
+    pub sender_pubkey: Vec<u8>,
+    pub client_message_id: i64,
+    /// Another device of the recipient already acked its copy.
+    pub delivered: bool,
+}
 
 #[derive(Debug, Clone)]
 pub struct PendingMessage {
-    pub _id: i64,
-    pub _recipient_pubkey: Vec<u8>,
+    pub id: i64,
+    pub recipient_pubkey: Vec<u8>,
+    pub group_id: Option<i64>,
+    pub client_message_id: i64,
     pub sender_pubkey: Vec<u8>,
     pub sender_enc_pubkey: Vec<u8>,
     pub encrypted_content: Vec<u8>,
-    pub _created_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
 }
 
 impl PendingMessage {
-    pub async fn save(
+    pub async fn queue(
         pool: &PgPool,
-        recipient_pubkey: &[u8],
-        sender_pubkey: &[u8],
-        sender_enc_pubkey: &[u8],
-        encrypted_content: Vec<u8>,
-    ) -> Result<(), sqlx::Error> {
-        sqlx::query(
-            "INSERT INTO pending_messages (recipient_pubkey, sender_pubkey, sender_enc_pubkey, encrypted_content)
-             VALUES ($1, $2, $3, $4)"
-        )
-            .bind(recipient_pubkey)
-            .bind(sender_pubkey)
-            .bind(sender_enc_pubkey)
-            .bind(encrypted_content)
-            .execute(pool)
-            .await?;
+        recipients: &[Vec<u8>],
+        message: NewPendingMessage<'_>,
+        quota: PendingQuota,
+    ) -> Result<Vec<Queued>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
 
-        Ok(())
+        // Always locked in the same order, so two fan-outs to overlapping groups cannot deadlock.
+        let mut locking: Vec<&[u8]> = recipients.iter().map(Vec::as_slice).collect();
+        locking.sort();
+        locking.dedup();
+
+        for recipient_pubkey in locking {
+            lock_recipient(&mut tx, recipient_pubkey).await?;
+        }
+
+        let mut results = Vec::with_capacity(recipients.len());
+
+        for recipient_pubkey in recipients {
+            results.push(queue_for_recipient(&mut tx, recipient_pubkey, &message, quota).await?);
+        }
+
+        tx.commit().await?;
+
+        Ok(results)
     }
 
-    pub async fn get_and_delete_for_user(
+    pub async fn get_for_device(
         pool: &PgPool,
         recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
     ) -> Result<Vec<PendingMessage>, sqlx::Error> {
-        let messages: Vec<_> = sqlx::query_as::<_, (i64, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, DateTime<Utc>)>(
-            "SELECT id, recipient_pubkey, sender_pubkey, sender_enc_pubkey, encrypted_content, created_at
+        let messages = sqlx::query_as::<_, (i64, Vec<u8>, Option<i64>, i64, Vec<u8>, Vec<u8>, Vec<u8>, DateTime<Utc>)>(
+            "SELECT id, recipient_pubkey, group_id, client_message_id, sender_pubkey, sender_enc_pubkey, encrypted_content, created_at
              FROM pending_messages
-             WHERE recipient_pubkey = $1
-             ORDER BY created_at ASC"
+             WHERE recipient_pubkey = $1 AND recipient_device_id = $2
+             ORDER BY id ASC"
         )
             .bind(recipient_pubkey)
+            .bind(recipient_device_id)
             .fetch_all(pool)
             .await?
             .into_iter()
-            .map(|(id, recipient_pubkey, sender_pubkey, sender_enc_pubkey, encrypted_content, created_at)| {
+            .map(|(id, recipient_pubkey, group_id, client_message_id, sender_pubkey, sender_enc_pubkey, encrypted_content, created_at)| {
                 PendingMessage {
-                    _id: id,
-                    _recipient_pubkey: recipient_pubkey,
+                    id,
+                    recipient_pubkey,
+                    group_id,
+                    client_message_id,
                     sender_pubkey,
                     sender_enc_pubkey,
                     encrypted_content,
-                    _created_at: created_at,
+                    created_at,
                 }
             })
             .collect();
 
-        if !messages.is_empty() {
-            sqlx::query("DELETE FROM pending_messages WHERE recipient_pubkey = $1")
-                .bind(recipient_pubkey)
-                .execute(pool)
-                .await?;
-        }
-
         Ok(messages)
     }
+
+    pub async fn acknowledge(
+        pool: &PgPool,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+        id: i64,
+    ) -> Result<bool, sqlx::Error> {
+        // The first ack of any copy marks the message delivered, its other copies no longer count as lost.
+        let (acked,): (i64,) = sqlx::query_as(
+            "WITH acked AS (
+                 DELETE FROM pending_messages
+                 WHERE id = $1 AND recipient_pubkey = $2 AND recipient_device_id = $3
+                 RETURNING sender_pubkey, recipient_pubkey, client_message_id
+             ), delivered AS (
+                 UPDATE sent_messages s SET delivered = TRUE
+                 FROM acked a
+                 WHERE s.sender_pubkey = a.sender_pubkey
+                   AND s.recipient_pubkey = a.recipient_pubkey
+                   AND s.client_message_id = a.client_message_id
+             )
+             SELECT COUNT(*) FROM acked",
+        )
+        .bind(id)
+        .bind(recipient_pubkey)
+        .bind(recipient_device_id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(acked > 0)
+    }
+
+    pub async fn purge_expired(
+        pool: &PgPool,
+        default_retention_days: i32,
+        limit: i64,
+    ) -> Result<Vec<ExpiredMessage>, sqlx::Error> {
+        let expired = sqlx::query_as::<_, (Vec<u8>, Vec<u8>, i64, bool)>(
+            "WITH expired AS (
+                 DELETE FROM pending_messages
+                 WHERE id IN (
+                     SELECT p.id
+                     FROM pending_messages p
+                     LEFT JOIN users u ON u.public_key = p.recipient_pubkey
+                     WHERE p.created_at < NOW() - make_interval(days => COALESCE(u.pending_retention_days, $1))
+                     ORDER BY p.id ASC
+                     LIMIT $2
+                 )
+                 RETURNING recipient_pubkey, sender_pubkey, client_message_id
+             )
+             SELECT e.recipient_pubkey, e.sender_pubkey, e.client_message_id, COALESCE(s.delivered, FALSE)
+             FROM expired e
+             LEFT JOIN sent_messages s
+                    ON s.sender_pubkey = e.sender_pubkey
+                   AND s.recipient_pubkey = e.recipient_pubkey
+                   AND s.client_message_id = e.client_message_id",
+        )
+        .bind(default_retention_days)
+        .bind(limit)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(
+            |(recipient_pubkey, sender_pubkey, client_message_id, delivered)| ExpiredMessage {
+                recipient_pubkey,
+                sender_pubkey,
+                client_message_id,
+                delivered,
+            },
+        )
+        .collect();
+
+        Ok(expired)
+    }
+
+    /// The subset of `client_message_ids` the sender really sent to the recipient.
+    pub async fn sent_ids(
+        pool: &PgPool,
+        sender_pubkey: &[u8],
+        recipient_pubkey: &[u8],
+        client_message_ids: &[i64],
+    ) -> Result<Vec<i64>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT client_message_id FROM sent_messages
+             WHERE sender_pubkey = $1 AND recipient_pubkey = $2 AND client_message_id = ANY($3)",
+        )
+        .bind(sender_pubkey)
+        .bind(recipient_pubkey)
+        .bind(client_message_ids)
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn purge_sent_older_than(pool: &PgPool, days: i32) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "DELETE FROM sent_messages WHERE created_at < NOW() - make_interval(days => $1)",
+        )
+        .bind(days)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn count(pool: &PgPool) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM pending_messages")
+            .fetch_one(pool)
+            .await
+    }
+}
+
+/// Serializes writers queueing for one recipient until the transaction ends.
+pub(super) async fn lock_recipient(
+    tx: &mut Transaction<'_, Postgres>,
+    recipient_pubkey: &[u8],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended(encode($1, 'hex'), 0))")
+        .bind(recipient_pubkey)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+async fn queue_for_recipient(
+    tx: &mut Transaction<'_, Postgres>,
+    recipient_pubkey: &[u8],
+    message: &NewPendingMessage<'_>,
+    quota: PendingQuota,
+) -> Result<Queued, sqlx::Error> {
+    let devices = sqlx::query_scalar::<_, Vec<u8>>(
+        "SELECT device_id FROM user_devices WHERE public_key = $1 ORDER BY created_at ASC",
+    )
+    .bind(recipient_pubkey)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    // Checked before the quota, resending a message must not store it twice.
+    let duplicate: bool = sqlx::query_scalar(
+        "SELECT EXISTS (
+             SELECT 1 FROM sent_messages
+             WHERE sender_pubkey = $1 AND recipient_pubkey = $2 AND client_message_id = $3
+         )",
+    )
+    .bind(message.sender_pubkey)
+    .bind(recipient_pubkey)
+    .bind(message.client_message_id)
+    .fetch_one(&mut **tx)
+    .await?;
+
+    if duplicate {
+        return Ok(Queued::Duplicate);
+    }
+
+    // Each message counts once, however many of the recipient's devices hold a copy.
+    let (messages, bytes): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*), COALESCE(SUM(size), 0)::BIGINT
+         FROM (
+             SELECT DISTINCT ON (sender_pubkey, client_message_id) octet_length(encrypted_content) AS size
+             FROM pending_messages
+             WHERE recipient_pubkey = $1
+             ORDER BY sender_pubkey, client_message_id
+         ) queued",
+    )
+    .bind(recipient_pubkey)
+    .fetch_one(&mut **tx)
+    .await?;
+
+    if messages + 1 > quota.max_messages {
+        return Ok(Queued::QueueFull);
+    }
+    if bytes + message.encrypted_content.len() as i64 > quota.max_bytes {
+        return Ok(Queued::QueueBytesExceeded);
+    }
+
+    sqlx::query(
+        "INSERT INTO sent_messages (sender_pubkey, recipient_pubkey, client_message_id)
+         VALUES ($1, $2, $3)",
+    )
+    .bind(message.sender_pubkey)
+    .bind(recipient_pubkey)
+    .bind(message.client_message_id)
+    .execute(&mut **tx)
+    .await?;
+
+    // Without a device the message waits unassigned until the first one registers.
+    let targets: Vec<Option<&[u8]>> = if devices.is_empty() {
+        vec![None]
+    } else {
+        devices.iter().map(|device_id| Some(device_id.as_slice())).collect()
+    };
+
+    let mut queued = Vec::with_capacity(devices.len());
+
+    for device_id in targets {
+        let (id,): (i64,) = sqlx::query_as(
+            "INSERT INTO pending_messages (recipient_pubkey, recipient_device_id, group_id, client_message_id, sender_pubkey, sender_enc_pubkey, encrypted_content)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             RETURNING id"
+        )
+            .bind(recipient_pubkey)
+            .bind(device_id)
+            .bind(message.group_id)
+            .bind(message.client_message_id)
+            .bind(message.sender_pubkey)
+            .bind(message.sender_enc_pubkey)
+            .bind(message.encrypted_content)
+            .fetch_one(&mut **tx)
+            .await?;
+
+        if let Some(device_id) = device_id {
+            queued.push((device_id.to_vec(), id));
+        }
+    }
+
+    Ok(Queued::Devices(queued))
 }
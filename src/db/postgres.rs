@@ -0,0 +1,456 @@
+use crate::db::models::UserProfile;
+use crate::db::storage::{
+    NewPendingMessage, NewReceipt, PendingQuota, Queued, Storage, StorageResult,
+};
+use crate::db::{
+    ExpiredMessage, Group, GroupRole, PendingMessage, PendingReceipt, RemovedDevices, UserDevice,
+};
+use crate::metrics::Metrics;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub struct PgStorage {
+    pool: PgPool,
+    metrics: Arc<Metrics>,
+}
+
+impl PgStorage {
+    pub fn new(pool: PgPool, metrics: Arc<Metrics>) -> Self {
+        Self { pool, metrics }
+    }
+
+    /// Runs a query and records how long it took under the storage method's name.
+    async fn timed<T>(
+        &self,
+        query: &'static str,
+        future: impl Future<Output = Result<T, sqlx::Error>>,
+    ) -> Result<T, sqlx::Error> {
+        let started = Instant::now();
+        let result = future.await;
+
+        self.metrics.db_query(query, started.elapsed());
+
+        result
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn find_user(&self, public_key: &[u8]) -> StorageResult<Option<UserProfile>> {
+        Ok(self
+            .timed(
+                "find_user",
+                UserProfile::find_by_pubkey(&self.pool, public_key),
+            )
+            .await?)
+    }
+
+    async fn known_users(&self, public_keys: &[Vec<u8>]) -> StorageResult<Vec<Vec<u8>>> {
+        Ok(self
+            .timed(
+                "known_users",
+                UserProfile::existing_keys(&self.pool, public_keys),
+            )
+            .await?)
+    }
+
+    async fn search_users(
+        &self,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> StorageResult<Vec<UserProfile>> {
+        Ok(self
+            .timed(
+                "search_users",
+                UserProfile::search(&self.pool, query, limit, offset),
+            )
+            .await?)
+    }
+
+    async fn create_user(
+        &self,
+        public_key: &[u8],
+        encryption_pubkey: &[u8],
+        first_name: &str,
+        username: Option<&str>,
+        last_name: Option<&str>,
+    ) -> StorageResult<UserProfile> {
+        Ok(self
+            .timed(
+                "create_user",
+                UserProfile::create(
+                    &self.pool,
+                    public_key,
+                    encryption_pubkey,
+                    first_name,
+                    username,
+                    last_name,
+                ),
+            )
+            .await?)
+    }
+
+    async fn update_user(
+        &self,
+        public_key: &[u8],
+        encryption_pubkey: &[u8],
+        first_name: &str,
+        username: Option<&str>,
+        last_name: Option<&str>,
+    ) -> StorageResult<UserProfile> {
+        Ok(self
+            .timed(
+                "update_user",
+                UserProfile::update_profile(
+                    &self.pool,
+                    public_key,
+                    encryption_pubkey,
+                    first_name,
+                    username,
+                    last_name,
+                ),
+            )
+            .await?)
+    }
+
+    async fn set_pending_retention(
+        &self,
+        public_key: &[u8],
+        days: Option<i32>,
+    ) -> StorageResult<bool> {
+        Ok(self
+            .timed(
+                "set_pending_retention",
+                UserProfile::set_pending_retention(&self.pool, public_key, days),
+            )
+            .await?)
+    }
+
+    async fn set_read_receipts(&self, public_key: &[u8], enabled: bool) -> StorageResult<bool> {
+        Ok(self
+            .timed(
+                "set_read_receipts",
+                UserProfile::set_read_receipts(&self.pool, public_key, enabled),
+            )
+            .await?)
+    }
+
+    async fn set_discoverable_by_name(
+        &self,
+        public_key: &[u8],
+        discoverable: bool,
+    ) -> StorageResult<bool> {
+        Ok(self
+            .timed(
+                "set_discoverable_by_name",
+                UserProfile::set_discoverable_by_name(&self.pool, public_key, discoverable),
+            )
+            .await?)
+    }
+
+    async fn set_last_seen(&self, public_key: &[u8], at: DateTime<Utc>) -> StorageResult<()> {
+        Ok(self
+            .timed(
+                "set_last_seen",
+                UserProfile::set_last_seen(&self.pool, public_key, at),
+            )
+            .await?)
+    }
+
+    async fn set_last_seen_visibility(
+        &self,
+        public_key: &[u8],
+        visibility: i16,
+    ) -> StorageResult<bool> {
+        Ok(self
+            .timed(
+                "set_last_seen_visibility",
+                UserProfile::set_last_seen_visibility(&self.pool, public_key, visibility),
+            )
+            .await?)
+    }
+
+    async fn register_device(
+        &self,
+        public_key: &[u8],
+        device_id: &[u8],
+        max_devices: i64,
+    ) -> StorageResult<RemovedDevices> {
+        Ok(self
+            .timed(
+                "register_device",
+                UserDevice::register(&self.pool, public_key, device_id, max_devices),
+            )
+            .await?)
+    }
+
+    async fn touch_device(&self, public_key: &[u8], device_id: &[u8]) -> StorageResult<()> {
+        Ok(self
+            .timed(
+                "touch_device",
+                UserDevice::touch(&self.pool, public_key, device_id),
+            )
+            .await?)
+    }
+
+    async fn remove_device(
+        &self,
+        public_key: &[u8],
+        device_id: &[u8],
+    ) -> StorageResult<RemovedDevices> {
+        Ok(self
+            .timed(
+                "remove_device",
+                UserDevice::remove(&self.pool, public_key, device_id),
+            )
+            .await?)
+    }
+
+    async fn purge_stale_devices(
+        &self,
+        older_than_days: i32,
+        limit: i64,
+    ) -> StorageResult<RemovedDevices> {
+        Ok(self
+            .timed(
+                "purge_stale_devices",
+                UserDevice::purge_stale(&self.pool, older_than_days, limit),
+            )
+            .await?)
+    }
+
+    async fn list_devices(&self, public_key: &[u8]) -> StorageResult<Vec<Vec<u8>>> {
+        Ok(self
+            .timed(
+                "list_devices",
+                UserDevice::list_for_user(&self.pool, public_key),
+            )
+            .await?)
+    }
+
+    async fn queue_pending(
+        &self,
+        recipients: &[Vec<u8>],
+        message: NewPendingMessage<'_>,
+        quota: PendingQuota,
+    ) -> StorageResult<Vec<Queued>> {
+        Ok(self
+            .timed(
+                "queue_pending",
+                PendingMessage::queue(&self.pool, recipients, message, quota),
+            )
+            .await?)
+    }
+
+    async fn count_pending(&self) -> StorageResult<i64> {
+        Ok(self
+            .timed("count_pending", PendingMessage::count(&self.pool))
+            .await?)
+    }
+
+    async fn pending_for_device(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+    ) -> StorageResult<Vec<PendingMessage>> {
+        Ok(self
+            .timed(
+                "pending_for_device",
+                PendingMessage::get_for_device(&self.pool, recipient_pubkey, recipient_device_id),
+            )
+            .await?)
+    }
+
+    async fn acknowledge_pending(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+        id: i64,
+    ) -> StorageResult<bool> {
+        Ok(self
+            .timed(
+                "acknowledge_pending",
+                PendingMessage::acknowledge(&self.pool, recipient_pubkey, recipient_device_id, id),
+            )
+            .await?)
+    }
+
+    async fn purge_expired_pending(
+        &self,
+        default_retention_days: i32,
+        limit: i64,
+    ) -> StorageResult<Vec<ExpiredMessage>> {
+        Ok(self
+            .timed(
+                "purge_expired_pending",
+                PendingMessage::purge_expired(&self.pool, default_retention_days, limit),
+            )
+            .await?)
+    }
+
+    async fn purge_sent_messages(&self, older_than_days: i32) -> StorageResult<u64> {
+        Ok(self
+            .timed(
+                "purge_sent_messages",
+                PendingMessage::purge_sent_older_than(&self.pool, older_than_days),
+            )
+            .await?)
+    }
+
+    async fn sent_message_ids(
+        &self,
+        sender_pubkey: &[u8],
+        recipient_pubkey: &[u8],
+        client_message_ids: &[i64],
+    ) -> StorageResult<Vec<i64>> {
+        Ok(self
+            .timed(
+                "sent_message_ids",
+                PendingMessage::sent_ids(
+                    &self.pool,
+                    sender_pubkey,
+                    recipient_pubkey,
+                    client_message_ids,
+                ),
+            )
+            .await?)
+    }
+
+    async fn save_receipt(
+        &self,
+        receipt: NewReceipt<'_>,
+        max_pending: i64,
+    ) -> StorageResult<Option<i64>> {
+        Ok(self
+            .timed(
+                "save_receipt",
+                PendingReceipt::save(
+                    &self.pool,
+                    receipt.recipient_pubkey,
+                    receipt.recipient_device_id,
+                    receipt.reader_pubkey,
+                    receipt.client_message_ids,
+                    receipt.kind,
+                    max_pending,
+                ),
+            )
+            .await?)
+    }
+
+    async fn pending_receipts_for_device(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+    ) -> StorageResult<Vec<PendingReceipt>> {
+        Ok(self
+            .timed(
+                "pending_receipts_for_device",
+                PendingReceipt::get_for_device(&self.pool, recipient_pubkey, recipient_device_id),
+            )
+            .await?)
+    }
+
+    async fn acknowledge_receipt(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+        id: i64,
+    ) -> StorageResult<bool> {
+        Ok(self
+            .timed(
+                "acknowledge_receipt",
+                PendingReceipt::acknowledge(&self.pool, recipient_pubkey, recipient_device_id, id),
+            )
+            .await?)
+    }
+
+    async fn purge_expired_receipts(&self, retention_days: i32) -> StorageResult<u64> {
+        Ok(self
+            .timed(
+                "purge_expired_receipts",
+                PendingReceipt::purge_older_than(&self.pool, retention_days),
+            )
+            .await?)
+    }
+
+    async fn create_group(
+        &self,
+        name: &str,
+        creator: &[u8],
+        members: &[Vec<u8>],
+        max_groups: i64,
+    ) -> StorageResult<Option<i64>> {
+        Ok(self
+            .timed(
+                "create_group",
+                Group::create(&self.pool, name, creator, members, max_groups),
+            )
+            .await?)
+    }
+
+    async fn group_role(
+        &self,
+        group_id: i64,
+        public_key: &[u8],
+    ) -> StorageResult<Option<GroupRole>> {
+        Ok(self
+            .timed(
+                "group_role",
+                Group::find_role(&self.pool, group_id, public_key),
+            )
+            .await?)
+    }
+
+    async fn group_members(&self, group_id: i64) -> StorageResult<Vec<Vec<u8>>> {
+        Ok(self
+            .timed("group_members", Group::list_members(&self.pool, group_id))
+            .await?)
+    }
+
+    async fn share_group(&self, first: &[u8], second: &[u8]) -> StorageResult<bool> {
+        Ok(self
+            .timed("share_group", Group::share_any(&self.pool, first, second))
+            .await?)
+    }
+
+    async fn add_group_member(
+        &self,
+        group_id: i64,
+        public_key: &[u8],
+        max_members: i64,
+    ) -> StorageResult<bool> {
+        Ok(self
+            .timed(
+                "add_group_member",
+                Group::add_member(&self.pool, group_id, public_key, max_members),
+            )
+            .await?)
+    }
+
+    async fn set_group_role(
+        &self,
+        group_id: i64,
+        public_key: &[u8],
+        role: GroupRole,
+    ) -> StorageResult<bool> {
+        Ok(self
+            .timed(
+                "set_group_role",
+                Group::set_role(&self.pool, group_id, public_key, role),
+            )
+            .await?)
+    }
+
+    async fn remove_group_member(&self, group_id: i64, public_key: &[u8]) -> StorageResult<bool> {
+        Ok(self
+            .timed(
+                "remove_group_member",
+                Group::remove_member(&self.pool, group_id, public_key),
+            )
+            .await?)
+    }
+}
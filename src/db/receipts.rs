@@ -0,0 +1,113 @@
+use crate::db::pending::lock_recipient;
+use sqlx::PgPool;
+
+#[derive(Debug, Clone)]
+pub struct PendingReceipt {
+    pub id: i64,
+    pub reader_pubkey: Vec<u8>,
+    pub client_message_ids: Vec<i64>,
+    pub kind: i16,
+}
+
+impl PendingReceipt {
+    /// Saves the receipt unless the recipient already has `max_pending` waiting across their devices.
+    pub async fn save(
+        pool: &PgPool,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+        reader_pubkey: &[u8],
+        client_message_ids: &[i64],
+        kind: i16,
+        max_pending: i64,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let mut tx = pool.begin().await?;
+
+        lock_recipient(&mut tx, recipient_pubkey).await?;
+
+        let (pending,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM pending_receipts WHERE recipient_pubkey = $1")
+                .bind(recipient_pubkey)
+                .fetch_one(&mut *tx)
+                .await?;
+
+        if pending >= max_pending {
+            return Ok(None);
+        }
+
+        let (id,): (i64,) = sqlx::query_as(
+            "INSERT INTO pending_receipts (recipient_pubkey, recipient_device_id, reader_pubkey, client_message_ids, kind)
+             VALUES ($1, $2, $3, $4, $5)
+             RETURNING id",
+        )
+        .bind(recipient_pubkey)
+        .bind(recipient_device_id)
+        .bind(reader_pubkey)
+        .bind(client_message_ids)
+        .bind(kind)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(id))
+    }
+
+    pub async fn get_for_device(
+        pool: &PgPool,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+    ) -> Result<Vec<PendingReceipt>, sqlx::Error> {
+        let receipts = sqlx::query_as::<_, (i64, Vec<u8>, Vec<i64>, i16)>(
+            "SELECT id, reader_pubkey, client_message_ids, kind
+             FROM pending_receipts
+             WHERE recipient_pubkey = $1 AND recipient_device_id = $2
+             ORDER BY id ASC",
+        )
+        .bind(recipient_pubkey)
+        .bind(recipient_device_id)
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(
+            |(id, reader_pubkey, client_message_ids, kind)| PendingReceipt {
+                id,
+                reader_pubkey,
+                client_message_ids,
+                kind,
+            },
+        )
+        .collect();
+
+        Ok(receipts)
+    }
+
+    pub async fn acknowledge(
+        pool: &PgPool,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+        id: i64,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "DELETE FROM pending_receipts
+             WHERE id = $1 AND recipient_pubkey = $2 AND recipient_device_id = $3",
+        )
+        .bind(id)
+        .bind(recipient_pubkey)
+        .bind(recipient_device_id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn purge_older_than(pool: &PgPool, days: i32) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(
+            "DELETE FROM pending_receipts WHERE created_at < NOW() - make_interval(days => $1)",
+        )
+        .bind(days)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
@@ -0,0 +1,253 @@
+use crate::db::models::UserProfile;
+use crate::db::{ExpiredMessage, GroupRole, PendingMessage, PendingReceipt, RemovedDevices};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Database(sqlx::Error),
+    Conflict(&'static str),
+    NotFound(&'static str),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Database(e) => write!(f, "Database error: {}", e),
+            StorageError::Conflict(what) => write!(f, "{}", what),
+            StorageError::NotFound(what) => write!(f, "{}", what),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<sqlx::Error> for StorageError {
+    fn from(e: sqlx::Error) -> Self {
+        StorageError::Database(e)
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+pub struct NewPendingMessage<'a> {
+    pub group_id: Option<i64>,
+    pub client_message_id: i64,
+    pub sender_pubkey: &'a [u8],
+    pub sender_enc_pubkey: &'a [u8],
+    pub encrypted_content: &'a [u8],
+}
+
+pub struct NewReceipt<'a> {
+    pub recipient_pubkey: &'a [u8],
+    pub recipient_device_id: &'a [u8],
+    pub reader_pubkey: &'a [u8],
+    pub client_message_ids: &'a [i64],
+    pub kind: i16,
+}
+
+/// How much may be queued for one recipient. Every device gets its own copy of a message,
+/// but a message counts once however many copies it has.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingQuota {
+    pub max_messages: i64,
+    pub max_bytes: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Queued {
+    /// Device id and message id of every copy. Empty when the recipient has no device yet,
+    /// the message then waits unassigned until a device registers.
+    Devices(Vec<(Vec<u8>, i64)>),
+    /// The sender already sent a message with this client message id to the recipient,
+    /// nothing new was stored.
+    Duplicate,
+    QueueFull,
+    QueueBytesExceeded,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn find_user(&self, public_key: &[u8]) -> StorageResult<Option<UserProfile>>;
+
+    /// The subset of `public_keys` that belongs to users with a profile.
+    async fn known_users(&self, public_keys: &[Vec<u8>]) -> StorageResult<Vec<Vec<u8>>>;
+
+    /// `query` is expected in lowercase, results are ordered best match first.
+    async fn search_users(
+        &self,
+        query: &str,
+        limit: i64,
+        offset: i64,
+    ) -> StorageResult<Vec<UserProfile>>;
+
+    async fn create_user(
+        &self,
+        public_key: &[u8],
+        encryption_pubkey: &[u8],
+        first_name: &str,
+        username: Option<&str>,
+        last_name: Option<&str>,
+    ) -> StorageResult<UserProfile>;
+
+    async fn update_user(
+        &self,
+        public_key: &[u8],
+        encryption_pubkey: &[u8],
+        first_name: &str,
+        username: Option<&str>,
+        last_name: Option<&str>,
+    ) -> StorageResult<UserProfile>;
+
+    async fn set_pending_retention(
+        &self,
+        public_key: &[u8],
+        days: Option<i32>,
+    ) -> StorageResult<bool>;
+
+    async fn set_read_receipts(&self, public_key: &[u8], enabled: bool) -> StorageResult<bool>;
+
+    async fn set_discoverable_by_name(
+        &self,
+        public_key: &[u8],
+        discoverable: bool,
+    ) -> StorageResult<bool>;
+
+    async fn set_last_seen(&self, public_key: &[u8], at: DateTime<Utc>) -> StorageResult<()>;
+
+    async fn set_last_seen_visibility(
+        &self,
+        public_key: &[u8],
+        visibility: i16,
+    ) -> StorageResult<bool>;
+
+    /// Registers the device or marks it seen. Past `max_devices` the devices seen least recently
+    /// are removed, like `remove_device` would.
+    async fn register_device(
+        &self,
+        public_key: &[u8],
+        device_id: &[u8],
+        max_devices: i64,
+    ) -> StorageResult<RemovedDevices>;
+
+    async fn touch_device(&self, public_key: &[u8], device_id: &[u8]) -> StorageResult<()>;
+
+    /// Drops the device with its queued copies and receipts. Nothing is removed for an unknown device.
+    async fn remove_device(
+        &self,
+        public_key: &[u8],
+        device_id: &[u8],
+    ) -> StorageResult<RemovedDevices>;
+
+    /// Removes up to `limit` devices not seen for `older_than_days`.
+    async fn purge_stale_devices(
+        &self,
+        older_than_days: i32,
+        limit: i64,
+    ) -> StorageResult<RemovedDevices>;
+
+    /// Ordered by registration.
+    async fn list_devices(&self, public_key: &[u8]) -> StorageResult<Vec<Vec<u8>>>;
+
+    /// Queues a copy for every device of each recipient, skipping recipients it would take over the
+    /// quota. Results are in the order of `recipients`. Everything is written in one transaction,
+    /// and checking and inserting is atomic per recipient so concurrent senders cannot overshoot.
+    async fn queue_pending(
+        &self,
+        recipients: &[Vec<u8>],
+        message: NewPendingMessage<'_>,
+        quota: PendingQuota,
+    ) -> StorageResult<Vec<Queued>>;
+
+    /// Undelivered messages across all recipients.
+    async fn count_pending(&self) -> StorageResult<i64>;
+
+    async fn pending_for_device(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+    ) -> StorageResult<Vec<PendingMessage>>;
+
+    async fn acknowledge_pending(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+        id: i64,
+    ) -> StorageResult<bool>;
+
+    async fn purge_expired_pending(
+        &self,
+        default_retention_days: i32,
+        limit: i64,
+    ) -> StorageResult<Vec<ExpiredMessage>>;
+
+    /// Forgets which messages were sent before the cut-off, receipts for them are refused from then on.
+    async fn purge_sent_messages(&self, older_than_days: i32) -> StorageResult<u64>;
+
+    /// The subset of `client_message_ids` that `sender_pubkey` sent to `recipient_pubkey`.
+    async fn sent_message_ids(
+        &self,
+        sender_pubkey: &[u8],
+        recipient_pubkey: &[u8],
+        client_message_ids: &[i64],
+    ) -> StorageResult<Vec<i64>>;
+
+    /// Returns None without saving once the recipient has `max_pending` receipts waiting.
+    async fn save_receipt(
+        &self,
+        receipt: NewReceipt<'_>,
+        max_pending: i64,
+    ) -> StorageResult<Option<i64>>;
+
+    async fn pending_receipts_for_device(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+    ) -> StorageResult<Vec<PendingReceipt>>;
+
+    async fn acknowledge_receipt(
+        &self,
+        recipient_pubkey: &[u8],
+        recipient_device_id: &[u8],
+        id: i64,
+    ) -> StorageResult<bool>;
+
+    async fn purge_expired_receipts(&self, retention_days: i32) -> StorageResult<u64>;
+
+    /// Returns None without creating anything once `creator` has created `max_groups` groups.
+    async fn create_group(
+        &self,
+        name: &str,
+        creator: &[u8],
+        members: &[Vec<u8>],
+        max_groups: i64,
+    ) -> StorageResult<Option<i64>>;
+
+    async fn group_role(
+        &self,
+        group_id: i64,
+        public_key: &[u8],
+    ) -> StorageResult<Option<GroupRole>>;
+
+    async fn group_members(&self, group_id: i64) -> StorageResult<Vec<Vec<u8>>>;
+
+    async fn share_group(&self, first: &[u8], second: &[u8]) -> StorageResult<bool>;
+
+    /// Checks the member count and inserts atomically. Returns false if the group is full or gone.
+    async fn add_group_member(
+        &self,
+        group_id: i64,
+        public_key: &[u8],
+        max_members: i64,
+    ) -> StorageResult<bool>;
+
+    async fn set_group_role(
+        &self,
+        group_id: i64,
+        public_key: &[u8],
+        role: GroupRole,
+    ) -> StorageResult<bool>;
+
+    async fn remove_group_member(&self, group_id: i64, public_key: &[u8]) -> StorageResult<bool>;
+}
@@ -0,0 +1,101 @@
+use crate::db::StorageError;
+use std::fmt;
+
+/// Sent to clients in `Packet::Error`. The numbers are part of the protocol, never reuse one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    Internal = 1,
+    MalformedPacket = 2,
+    UnsupportedPacket = 3,
+    InvalidArgument = 4,
+    NotFound = 5,
+    Conflict = 6,
+    ProfileRequired = 7,
+    TryAgainLater = 8,
+    LimitReached = 9,
+    Unauthorized = 10,
+}
+
+#[derive(Debug)]
+pub enum ServerError {
+    Storage(StorageError),
+    /// Writing to a session failed, usually because it is gone.
+    Send(std::io::Error),
+    InvalidArgument(&'static str),
+    /// The packet could not be decoded.
+    MalformedPacket(String),
+    /// The sender has to set a profile before this request.
+    ProfileRequired,
+    UnsupportedPacket(u8),
+    TryAgainLater(&'static str),
+    /// The sender already uses as much of something as one user may.
+    LimitReached(&'static str),
+    /// The packet needs a logged-in session.
+    Unauthorized,
+}
+
+pub type ServerResult<T> = Result<T, ServerError>;
+
+impl ServerError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ServerError::Storage(StorageError::Conflict(_)) => ErrorCode::Conflict,
+            ServerError::Storage(StorageError::NotFound(_)) => ErrorCode::NotFound,
+            ServerError::Storage(StorageError::Database(e))
+                if e.as_database_error()
+                    .is_some_and(|e| e.is_unique_violation()) =>
+            {
+                ErrorCode::Conflict
+            }
+            ServerError::Storage(StorageError::Database(_)) | ServerError::Send(_) => {
+                ErrorCode::Internal
+            }
+            ServerError::InvalidArgument(_) => ErrorCode::InvalidArgument,
+            ServerError::MalformedPacket(_) => ErrorCode::MalformedPacket,
+            ServerError::ProfileRequired => ErrorCode::ProfileRequired,
+            ServerError::UnsupportedPacket(_) => ErrorCode::UnsupportedPacket,
+            ServerError::TryAgainLater(_) => ErrorCode::TryAgainLater,
+            ServerError::LimitReached(_) => ErrorCode::LimitReached,
+            ServerError::Unauthorized => ErrorCode::Unauthorized,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Storage(e) => write!(f, "{}", e),
+            ServerError::Send(e) => write!(f, "Send failed: {}", e),
+            ServerError::InvalidArgument(what) => write!(f, "Invalid {}", what),
+            ServerError::MalformedPacket(why) => write!(f, "Malformed packet: {}", why),
+            ServerError::ProfileRequired => write!(f, "Sender has no profile"),
+            ServerError::UnsupportedPacket(id) => write!(f, "Unsupported packet {:02X}", id),
+            ServerError::TryAgainLater(why) => write!(f, "{}", why),
+            ServerError::LimitReached(what) => write!(f, "Too many {}", what),
+            ServerError::Unauthorized => write!(f, "Not logged in"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Storage(e) => Some(e),
+            ServerError::Send(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<StorageError> for ServerError {
+    fn from(e: StorageError) -> Self {
+        ServerError::Storage(e)
+    }
+}
+
+impl From<std::io::Error> for ServerError {
+    fn from(e: std::io::Error) -> Self {
+        ServerError::Send(e)
+    }
+}
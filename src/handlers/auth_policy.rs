@@ -0,0 +1,14 @@
+use hnet_protocol::Packet;
+
+pub enum Access {
+    Public,
+    Authenticated,
+}
+
+// Anything not listed here needs a logged-in session, so new packets are closed by default.
+pub fn required_access(packet: &Packet) -> Access {
+    match packet {
+        Packet::GetChallenge { .. } | Packet::LoginRequest { .. } | Packet::Ping => Access::Public,
+        _ => Access::Authenticated,
+    }
+}
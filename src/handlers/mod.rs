@@ -1,3 +1,4 @@
+mod auth_policy;
 pub mod packet_handler;
 
-pub use packet_handler::PacketHandler;
+pub use packet_handler::{PacketHandler, Services};
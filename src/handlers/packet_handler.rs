@@ -1,70 +1,113 @@
+use crate::error::{ServerError, ServerResult};
+use crate::handlers::auth_policy::{Access, required_access};
 use crate::logging::Logger;
-use crate::services::{AuthService, MessageService, UserService};
-use crate::session::SessionManager;
+use crate::services::{
+    AuthService, DeviceService, GroupService, LoggedIn, MessageService, PresenceService,
+    ReceiptService, TypingService, UserService,
+};
+use crate::session::{SessionId, SessionManager};
 use hnet_protocol::Packet;
 use std::sync::Arc;
 
+/// The services packets are dispatched to.
+pub struct Services {
+    pub auth_service: Arc<AuthService>,
+    pub user_service: Arc<UserService>,
+    pub message_service: Arc<MessageService>,
+    pub group_service: Arc<GroupService>,
+    pub receipt_service: Arc<ReceiptService>,
+    pub presence_service: Arc<PresenceService>,
+    pub typing_service: Arc<TypingService>,
+    pub device_service: Arc<DeviceService>,
+}
+
 pub struct PacketHandler {
     auth_service: Arc<AuthService>,
     user_service: Arc<UserService>,
     message_service: Arc<MessageService>,
+    group_service: Arc<GroupService>,
+    receipt_service: Arc<ReceiptService>,
+    presence_service: Arc<PresenceService>,
+    typing_service: Arc<TypingService>,
+    device_service: Arc<DeviceService>,
     session_manager: Arc<SessionManager>,
     logger: Logger,
 }
 
 impl PacketHandler {
-    pub fn new(
-        auth_service: Arc<AuthService>,
-        user_service: Arc<UserService>,
-        message_service: Arc<MessageService>,
-        session_manager: Arc<SessionManager>,
-    ) -> Self {
+    pub fn new(services: Services, session_manager: Arc<SessionManager>) -> Self {
+        let Services {
+            auth_service,
+            user_service,
+            message_service,
+            group_service,
+            receipt_service,
+            presence_service,
+            typing_service,
+            device_service,
+        } = services;
+
         Self {
             auth_service,
             user_service,
             message_service,
+            group_service,
+            receipt_service,
+            presence_service,
+            typing_service,
+            device_service,
             session_manager,
             logger: Logger::new("NETWORK"),
         }
     }
 
-    pub async fn handle(
-        &self,
-        sender_pubkey: Option<Vec<u8>>,
-        packet: Packet,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    /// Runs inside the connection's `Logger::scope`, which tags the log lines with it.
+    pub async fn handle(&self, sender: Option<SessionId>, packet: Packet) -> ServerResult<()> {
+        if let Access::Authenticated = required_access(&packet) {
+            let authenticated = match sender {
+                Some(ref sender) => self.session_manager.is_authenticated(sender),
+                None => false,
+            };
+
+            if !authenticated {
+                self.logger.w(&format!(
+                    "Rejected packet {:02X} from unauthenticated session",
+                    packet.get_id()
+                ));
+
+                return Err(ServerError::Unauthorized);
+            }
+        }
+
         match packet {
             Packet::GetChallenge { public_key } => {
-                let challenge = self
-                    .auth_service
-                    .generate_challenge(public_key.clone())
-                    .await;
-
-                if let Some(sender) = sender_pubkey {
-                    self.session_manager
-                        .send_to_user(&sender, Packet::Challenge { challenge })
-                        .await?;
+                if let Some(sender) = sender {
+                    match self
+                        .auth_service
+                        .generate_challenge(sender.clone(), public_key)
+                        .await
+                    {
+                        Some(challenge) => {
+                            self.session_manager
+                                .send_to_device(&sender, Packet::Challenge { challenge })
+                                .await?;
+                        }
+                        None => {
+                            return Err(ServerError::TryAgainLater(
+                                "Too many outstanding challenges",
+                            ));
+                        }
+                    }
                 }
             }
 
             Packet::LoginRequest {
                 public_key,
                 signature,
+                device_id,
             } => {
-                let (success, profile_exists) = self
-                    .auth_service
-                    .verify_login(&public_key, &signature)
-                    .await?;
-
-                if let Some(sender) = sender_pubkey {
-                    self.session_manager
-                        .send_to_user(
-                            &sender,
-                            Packet::LoginResponse {
-                                success,
-                                profile_exists,
-                            },
-                        )
+                if let Some(sender) = sender {
+                    self.handle_login(&sender, public_key, device_id, signature)
                         .await?;
                 }
             }
@@ -75,45 +118,230 @@ impl PacketHandler {
                 username,
                 last_name,
             } => {
-                if let Some(pubkey) = sender_pubkey {
+                if let Some(sender) = sender {
                     self.user_service
-                        .set_profile(&pubkey, encryption_pubkey, first_name, username, last_name)
+                        .set_profile(&sender, encryption_pubkey, first_name, username, last_name)
+                        .await?;
+                }
+            }
+
+            Packet::SetPendingRetention { days } => {
+                if let Some(sender) = sender {
+                    self.user_service.set_pending_retention(&sender, days).await?;
+                }
+            }
+
+            Packet::SetReadReceipts { enabled } => {
+                if let Some(sender) = sender {
+                    self.user_service.set_read_receipts(&sender, enabled).await?;
+                }
+            }
+
+            Packet::SetLastSeenVisibility { visibility } => {
+                if let Some(sender) = sender {
+                    self.presence_service
+                        .set_last_seen_visibility(&sender, visibility)
                         .await?;
                 }
             }
 
-            Packet::SearchUser { query } => {
-                if let Some(pubkey) = sender_pubkey {
-                    self.user_service.search_user(&pubkey, query).await?;
+            Packet::SubscribePresence { public_keys } => {
+                if let Some(sender) = sender {
+                    self.presence_service.subscribe(&sender, public_keys).await?;
+                }
+            }
+
+            Packet::UnsubscribePresence { public_keys } => {
+                if let Some(sender) = sender {
+                    self.presence_service.unsubscribe(&sender, public_keys).await;
+                }
+            }
+
+            Packet::SetNameDiscovery { enabled } => {
+                if let Some(sender) = sender {
+                    self.user_service.set_name_discovery(&sender, enabled).await?;
+                }
+            }
+
+            Packet::SearchUser {
+                query,
+                offset,
+                limit,
+            } => {
+                if let Some(sender) = sender {
+                    self.user_service
+                        .search_user(&sender, query, offset, limit)
+                        .await?;
                 }
             }
 
             Packet::SendMessage {
+                client_message_id,
                 recipient_pubkey,
                 encrypted_content,
             } => {
-                if let Some(sender) = sender_pubkey {
-                    if let Ok(sender_enc_pubkey) =
-                        self.user_service.get_encryption_pubkey(&sender).await
-                    {
-                        self.message_service
-                            .route_message(
-                                &sender,
-                                &sender_enc_pubkey,
-                                recipient_pubkey,
-                                encrypted_content,
-                            )
-                            .await?;
-                    }
+                if let Some(sender) = sender {
+                    let sender_enc_pubkey = self
+                        .user_service
+                        .get_encryption_pubkey(&sender.public_key)
+                        .await?;
+
+                    self.message_service
+                        .route_message(
+                            &sender,
+                            &sender_enc_pubkey,
+                            client_message_id,
+                            recipient_pubkey,
+                            encrypted_content,
+                        )
+                        .await?;
+                }
+            }
+
+            Packet::SendGroupMessage {
+                client_message_id,
+                group_id,
+                encrypted_content,
+            } => {
+                if let Some(sender) = sender {
+                    let sender_enc_pubkey = self
+                        .user_service
+                        .get_encryption_pubkey(&sender.public_key)
+                        .await?;
+
+                    self.message_service
+                        .route_group_message(
+                            &sender,
+                            &sender_enc_pubkey,
+                            client_message_id,
+                            group_id,
+                            encrypted_content,
+                        )
+                        .await?;
+                }
+            }
+
+            Packet::CreateGroup { name, members } => {
+                if let Some(sender) = sender {
+                    self.group_service
+                        .create_group(&sender, name, members)
+                        .await?;
                 }
             }
 
-            _ => {
-                self.logger
-                    .w(&format!("Unhandled packet id: {:02X}", packet.get_id()));
+            Packet::AddGroupMember {
+                group_id,
+                public_key,
+            } => {
+                if let Some(sender) = sender {
+                    self.group_service
+                        .add_member(&sender, group_id, public_key)
+                        .await?;
+                }
             }
+
+            Packet::RemoveGroupMember {
+                group_id,
+                public_key,
+            } => {
+                if let Some(sender) = sender {
+                    self.group_service
+                        .remove_member(&sender, group_id, public_key)
+                        .await?;
+                }
+            }
+
+            Packet::SetGroupAdmin {
+                group_id,
+                public_key,
+                admin,
+            } => {
+                if let Some(sender) = sender {
+                    self.group_service
+                        .set_admin(&sender, group_id, public_key, admin)
+                        .await?;
+                }
+            }
+
+            Packet::LeaveGroup { group_id } => {
+                if let Some(sender) = sender {
+                    self.group_service.leave_group(&sender, group_id).await?;
+                }
+            }
+
+            Packet::RemoveDevice { device_id } => {
+                if let Some(sender) = sender {
+                    self.device_service.remove(&sender, device_id).await?;
+                }
+            }
+
+            Packet::MessageAck { message_id } => {
+                if let Some(recipient) = sender {
+                    self.message_service
+                        .acknowledge_message(&recipient, message_id)
+                        .await?;
+                }
+            }
+
+            Packet::ReceiptAck { receipt_id } => {
+                if let Some(recipient) = sender {
+                    self.receipt_service
+                        .acknowledge_receipt(&recipient, receipt_id)
+                        .await?;
+                }
+            }
+
+            Packet::Typing {
+                recipient_pubkey,
+                kind,
+            } => {
+                if let Some(sender) = sender {
+                    self.typing_service.relay(&sender, recipient_pubkey, kind).await;
+                }
+            }
+
+            Packet::SendReceipt {
+                sender_pubkey,
+                client_message_ids,
+                kind,
+            } => {
+                if let Some(reader) = sender {
+                    self.receipt_service
+                        .send_receipt(&reader, sender_pubkey, client_message_ids, kind)
+                        .await?;
+                }
+            }
+
+            _ => return Err(ServerError::UnsupportedPacket(packet.get_id())),
         }
 
         Ok(())
     }
+
+    pub async fn handle_login(
+        &self,
+        session: &SessionId,
+        public_key: Vec<u8>,
+        device_id: Vec<u8>,
+        signature: Vec<u8>,
+    ) -> ServerResult<Option<LoggedIn>> {
+        let logged_in = self
+            .auth_service
+            .verify_login(session, &public_key, &device_id, &signature)
+            .await?;
+
+        self.session_manager
+            .send_to_device(
+                logged_in.as_ref().map_or(session, |logged_in| &logged_in.session),
+                Packet::LoginResponse {
+                    success: logged_in.is_some(),
+                    profile_exists: logged_in
+                        .as_ref()
+                        .is_some_and(|logged_in| logged_in.profile_exists),
+                },
+            )
+            .await?;
+
+        Ok(logged_in)
+    }
 }
@@ -1 +1,4 @@
+pub mod rate_limit;
 pub mod server;
+pub mod tls;
+pub mod transport;
@@ -0,0 +1,299 @@
+use crate::config::{BucketConfig, RateLimitConfig};
+use dashmap::DashMap;
+use hnet_protocol::Packet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const MAX_TRACKED_BUCKETS: usize = 100_000;
+
+/// Packets are grouped by cost, each group has its own budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PacketClass {
+    /// Any frame, decoded or not. Always charged to the address.
+    Frame,
+    Auth,
+    Search,
+    Message,
+    Other,
+}
+
+impl PacketClass {
+    pub fn of(packet: &Packet) -> Self {
+        match packet {
+            Packet::GetChallenge { .. } | Packet::LoginRequest { .. } => PacketClass::Auth,
+            Packet::SearchUser { .. } => PacketClass::Search,
+            Packet::SendMessage { .. } | Packet::SendGroupMessage { .. } => PacketClass::Message,
+            _ => PacketClass::Other,
+        }
+    }
+}
+
+/// Who pays for a packet: the address before login, the key afterwards.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Ip(IpAddr),
+    Key(Vec<u8>),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn full(config: &BucketConfig) -> Self {
+        Self {
+            tokens: config.burst as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, config: &BucketConfig) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * config.per_second).min(config.burst as f64);
+        self.refilled_at = now;
+    }
+
+    /// Takes one token, or says how long until one is available.
+    fn take(&mut self, config: &BucketConfig) -> Result<(), Duration> {
+        self.refill(config);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / config.per_second,
+            ))
+        }
+    }
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: DashMap<(Subject, PacketClass), TokenBucket>,
+    connections: DashMap<IpAddr, usize>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: DashMap::new(),
+            connections: DashMap::new(),
+        }
+    }
+
+    pub fn max_violations(&self) -> u32 {
+        self.config.max_violations
+    }
+
+    /// Charges a raw frame to the peer address before anything is spent on decoding it.
+    pub fn check_frame(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.take(Subject::Ip(ip), PacketClass::Frame)
+    }
+
+    /// Charges a packet to the peer address, or to the key once the connection is logged in.
+    pub fn check(
+        &self,
+        ip: IpAddr,
+        public_key: Option<&[u8]>,
+        packet: &Packet,
+    ) -> Result<(), Duration> {
+        let subject = match public_key {
+            Some(public_key) => Subject::Key(public_key.to_vec()),
+            None => Subject::Ip(ip),
+        };
+
+        self.take(subject, PacketClass::of(packet))
+    }
+
+    fn take(&self, subject: Subject, class: PacketClass) -> Result<(), Duration> {
+        let config = self.bucket_config(class);
+
+        if self.buckets.len() >= MAX_TRACKED_BUCKETS {
+            self.prune();
+        }
+
+        self.buckets
+            .entry((subject, class))
+            .or_insert_with(|| TokenBucket::full(config))
+            .take(config)
+    }
+
+    /// Reserves a connection slot for the address, released when the slot is dropped.
+    pub fn try_connect(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionSlot> {
+        let mut connections = self.connections.entry(ip).or_insert(0);
+
+        if *connections >= self.config.max_connections_per_ip {
+            return None;
+        }
+
+        *connections += 1;
+
+        Some(ConnectionSlot {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+
+    fn bucket_config(&self, class: PacketClass) -> &BucketConfig {
+        match class {
+            PacketClass::Frame => &self.config.frames,
+            PacketClass::Auth => &self.config.auth,
+            PacketClass::Search => &self.config.search,
+            PacketClass::Message => &self.config.message,
+            PacketClass::Other => &self.config.other,
+        }
+    }
+
+    // A bucket that has refilled completely holds no state worth keeping.
+    fn prune(&self) {
+        self.buckets.retain(|(_, class), bucket| {
+            let config = self.bucket_config(*class);
+            bucket.refill(config);
+            bucket.tokens < config.burst as f64
+        });
+    }
+}
+
+pub struct ConnectionSlot {
+    limiter: Arc<RateLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limiter
+            .connections
+            .remove_if_mut(&self.ip, |_, connections| {
+                *connections -= 1;
+                *connections == 0
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    // Refills so slowly that no token comes back while a test runs.
+    fn bucket(burst: u32) -> BucketConfig {
+        BucketConfig {
+            per_second: 0.001,
+            burst,
+        }
+    }
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            max_connections_per_ip: 2,
+            frames: bucket(3),
+            auth: bucket(2),
+            other: bucket(2),
+            ..RateLimitConfig::default()
+        })
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_throttles() {
+        let config = bucket(3);
+        let mut bucket = TokenBucket::full(&config);
+
+        for _ in 0..3 {
+            assert!(bucket.take(&config).is_ok());
+        }
+
+        let retry_after = bucket.take(&config).unwrap_err();
+        assert!(retry_after > Duration::from_secs(900));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let config = BucketConfig {
+            per_second: 10.0,
+            burst: 1,
+        };
+        let mut bucket = TokenBucket::full(&config);
+
+        assert!(bucket.take(&config).is_ok());
+        assert!(bucket.take(&config).is_err());
+
+        bucket.refilled_at -= Duration::from_millis(150);
+        assert!(bucket.take(&config).is_ok());
+
+        // Never more than the burst, however long it waited.
+        bucket.refilled_at -= Duration::from_secs(60);
+        bucket.refill(&config);
+        assert_eq!(bucket.tokens, 1.0);
+    }
+
+    #[test]
+    fn frames_are_charged_per_address() {
+        let limiter = limiter();
+
+        for _ in 0..3 {
+            assert!(limiter.check_frame(IP).is_ok());
+        }
+
+        assert!(limiter.check_frame(IP).is_err());
+        assert!(limiter.check_frame(OTHER_IP).is_ok());
+    }
+
+    #[test]
+    fn packets_are_charged_to_the_key_after_login() {
+        let limiter = limiter();
+        let challenge = Packet::GetChallenge {
+            public_key: b"key".to_vec(),
+        };
+
+        for _ in 0..2 {
+            assert!(limiter.check(IP, None, &challenge).is_ok());
+        }
+        assert!(limiter.check(IP, None, &challenge).is_err());
+
+        // The key has its own budget, and so has every other class.
+        assert!(limiter.check(IP, Some(b"key"), &challenge).is_ok());
+        assert!(limiter.check(IP, None, &Packet::Ping).is_ok());
+    }
+
+    #[test]
+    fn connection_slots_are_capped_and_released() {
+        let limiter = Arc::new(limiter());
+
+        let first = limiter.try_connect(IP).unwrap();
+        let _second = limiter.try_connect(IP).unwrap();
+        assert!(limiter.try_connect(IP).is_none());
+        assert!(limiter.try_connect(OTHER_IP).is_some());
+
+        drop(first);
+        assert!(limiter.try_connect(IP).is_some());
+    }
+
+    #[test]
+    fn prune_drops_only_full_buckets() {
+        let limiter = limiter();
+
+        limiter.check_frame(IP).unwrap();
+        limiter.buckets.insert(
+            (Subject::Ip(OTHER_IP), PacketClass::Frame),
+            TokenBucket::full(&bucket(3)),
+        );
+
+        limiter.prune();
+
+        assert_eq!(limiter.buckets.len(), 1);
+        assert!(
+            limiter
+                .buckets
+                .contains_key(&(Subject::Ip(IP), PacketClass::Frame))
+        );
+    }
+}
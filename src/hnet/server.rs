@@ -1,15 +1,37 @@
 use colored::Colorize;
-use sqlx::PgPool;
+use futures_util::StreamExt;
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
 
 use tokio_util::sync::CancellationToken;
-
-use crate::handlers::PacketHandler;
+use tokio_util::task::TaskTracker;
+
+use crate::config::{Config, SessionConfig};
+use crate::db::Storage;
+use crate::error::{ErrorCode, ServerError};
+use crate::handlers::{PacketHandler, Services};
+use crate::hnet::rate_limit::RateLimiter;
+use crate::hnet::transport::{PacketSink, PacketSource, StreamSink, StreamSource, WsSink, WsSource};
 use crate::logging::Logger;
-use crate::services::{AuthService, MessageService, UserService};
-use crate::session::{Session, SessionManager};
-use hnet_protocol::{Packet, RawPacket};
+use crate::metrics::{Metrics, MetricsExporter};
+use crate::services::{
+    AuthService, DeviceService, GroupService, MaintenanceService, MessageService,
+    PresenceService, ReceiptService, TypingService, UserService,
+};
+use crate::session::{ConnectionId, Removal, Session, SessionId, SessionManager};
+use hnet_protocol::Packet;
+
+// A client that has not finished the TLS or WebSocket handshake by then is dropped, it holds a
+// connection slot and is invisible to the idle reaper until it has a session.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Room for keys, ids and a full group member list on top of the largest encrypted content.
+const PACKET_OVERHEAD_BYTES: usize = 16 * 1024;
 
 pub struct Server {
     host: String,
@@ -17,45 +39,134 @@ pub struct Server {
     logger: Logger,
     session_manager: Arc<SessionManager>,
     packet_handler: Arc<PacketHandler>,
+    auth_service: Arc<AuthService>,
     message_service: Arc<MessageService>,
+    receipt_service: Arc<ReceiptService>,
+    presence_service: Arc<PresenceService>,
+    device_service: Arc<DeviceService>,
+    maintenance_service: Arc<MaintenanceService>,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    metrics_exporter: Arc<MetricsExporter>,
+    tls_acceptor: Option<TlsAcceptor>,
+    ws_port: Option<u16>,
+    max_packet_bytes: usize,
+    metrics_addr: Option<String>,
+    session_config: SessionConfig,
+    shutdown_timeout: Duration,
+    // Every task the server spawns, so shutdown can wait for them instead of cutting them off.
+    tracker: TaskTracker,
 }
 
 impl Server {
-    pub fn new(host: String, port: u16, db_pool: PgPool) -> Self {
-        let session_manager = Arc::new(SessionManager::new());
+    pub fn new(config: &Config, storage: Arc<dyn Storage>, metrics: Arc<Metrics>) -> Self {
+        let session_manager = Arc::new(SessionManager::new(
+            config.session.enc_pubkey_cache_size,
+        ));
+
+        let receipt_service = Arc::new(ReceiptService::new(
+            Arc::clone(&session_manager),
+            Arc::clone(&storage),
+        ));
+
+        let device_service = Arc::new(DeviceService::new(
+            Arc::clone(&session_manager),
+            Arc::clone(&storage),
+            Arc::clone(&receipt_service),
+        ));
 
         let auth_service = Arc::new(AuthService::new(
             Arc::clone(&session_manager),
-            db_pool.clone(),
+            Arc::clone(&storage),
+            Arc::clone(&device_service),
+            Arc::clone(&metrics),
         ));
 
         let user_service = Arc::new(UserService::new(
             Arc::clone(&session_manager),
-            db_pool.clone(),
+            Arc::clone(&storage),
+            config.retention.max_days,
         ));
 
         let message_service = Arc::new(MessageService::new(
             Arc::clone(&session_manager),
-            db_pool.clone(),
+            Arc::clone(&storage),
+            &config.quota,
+            Arc::clone(&metrics),
+        ));
+
+        let group_service = Arc::new(GroupService::new(
+            Arc::clone(&session_manager),
+            Arc::clone(&storage),
+        ));
+
+        let presence_service = Arc::new(PresenceService::new(
+            Arc::clone(&session_manager),
+            Arc::clone(&storage),
+        ));
+
+        let typing_service = Arc::new(TypingService::new(Arc::clone(&session_manager)));
+
+        let maintenance_service = Arc::new(MaintenanceService::new(
+            Arc::clone(&receipt_service),
+            Arc::clone(&device_service),
+            Arc::clone(&storage),
+            Arc::clone(&metrics),
+            &config.retention,
+        ));
+
+        let metrics_exporter = Arc::new(MetricsExporter::new(
+            Arc::clone(&metrics),
+            Arc::clone(&session_manager),
         ));
 
         let packet_handler = Arc::new(PacketHandler::new(
-            auth_service,
-            user_service,
-            Arc::clone(&message_service),
+            Services {
+                auth_service: Arc::clone(&auth_service),
+                user_service,
+                message_service: Arc::clone(&message_service),
+                group_service,
+                receipt_service: Arc::clone(&receipt_service),
+                presence_service: Arc::clone(&presence_service),
+                typing_service,
+                device_service: Arc::clone(&device_service),
+            },
             Arc::clone(&session_manager),
         ));
 
         Self {
-            host,
+            host: config.server.host.clone(),
             logger: Logger::new("SERVER"),
-            port,
+            port: config.server.port,
             session_manager,
             packet_handler,
+            auth_service,
             message_service,
+            receipt_service,
+            presence_service,
+            device_service,
+            maintenance_service,
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limit.clone())),
+            metrics,
+            metrics_exporter,
+            tls_acceptor: None,
+            ws_port: config.server.ws_port,
+            max_packet_bytes: config.quota.max_message_bytes as usize + PACKET_OVERHEAD_BYTES,
+            metrics_addr: config
+                .metrics
+                .port
+                .map(|port| format!("{}:{}", config.metrics.host, port)),
+            session_config: config.session.clone(),
+            shutdown_timeout: config.server.shutdown_timeout(),
+            tracker: TaskTracker::new(),
         }
     }
 
+    pub fn with_tls(mut self, tls_acceptor: TlsAcceptor) -> Self {
+        self.tls_acceptor = Some(tls_acceptor);
+        self
+    }
+
     pub async fn listen(&self, shutdown_token: CancellationToken) {
         let addr = format!("{}:{}", self.host, self.port);
 
@@ -67,37 +178,110 @@ impl Server {
             }
         };
 
-        self.logger
-            .i(&format!("Server listening on {}", addr.bright_green()));
+        let tls_suffix = if self.tls_acceptor.is_some() { " (TLS)" } else { "" };
+
+        self.logger.i(&format!(
+            "Server listening on {}{}",
+            addr.bright_green(),
+            tls_suffix
+        ));
+
+        let ws_listener = match self.ws_port {
+            Some(ws_port) => {
+                let ws_addr = format!("{}:{}", self.host, ws_port);
+
+                match TcpListener::bind(&ws_addr).await {
+                    Ok(l) => {
+                        self.logger.i(&format!(
+                            "WebSocket listening on {}{}",
+                            ws_addr.bright_green(),
+                            tls_suffix
+                        ));
+                        Some(l)
+                    }
+                    Err(e) => {
+                        self.logger
+                            .e(&format!("Failed to bind to {}: {}", ws_addr, e));
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        if let Some(metrics_addr) = &self.metrics_addr {
+            match TcpListener::bind(metrics_addr).await {
+                Ok(metrics_listener) => {
+                    self.logger.i(&format!(
+                        "Metrics available at http://{}/metrics",
+                        metrics_addr.bright_green()
+                    ));
+
+                    self.tracker.spawn({
+                        let metrics_exporter = Arc::clone(&self.metrics_exporter);
+                        let tracker = self.tracker.clone();
+                        let shutdown_token = shutdown_token.child_token();
+                        async move {
+                            metrics_exporter
+                                .run(metrics_listener, tracker, shutdown_token)
+                                .await
+                        }
+                    });
+                }
+                Err(e) => {
+                    self.logger
+                        .e(&format!("Failed to bind to {}: {}", metrics_addr, e));
+                    return;
+                }
+            }
+        }
+
+        self.tracker.spawn({
+            let maintenance_service = Arc::clone(&self.maintenance_service);
+            let shutdown_token = shutdown_token.child_token();
+            async move { maintenance_service.run(shutdown_token).await }
+        });
+
+        self.tracker.spawn({
+            let session_manager = Arc::clone(&self.session_manager);
+            let session_config = self.session_config.clone();
+            let shutdown_token = shutdown_token.child_token();
+            async move {
+                session_manager
+                    .run_reaper(
+                        session_config.idle_timeout(),
+                        session_config.ping_after(),
+                        session_config.reap_interval(),
+                        shutdown_token,
+                    )
+                    .await
+            }
+        });
+
+        let context = ConnectionContext {
+            session_manager: Arc::clone(&self.session_manager),
+            packet_handler: Arc::clone(&self.packet_handler),
+            auth_service: Arc::clone(&self.auth_service),
+            message_service: Arc::clone(&self.message_service),
+            receipt_service: Arc::clone(&self.receipt_service),
+            presence_service: Arc::clone(&self.presence_service),
+            device_service: Arc::clone(&self.device_service),
+            rate_limiter: Arc::clone(&self.rate_limiter),
+            metrics: Arc::clone(&self.metrics),
+            tls_acceptor: self.tls_acceptor.clone(),
+            max_packet_bytes: self.max_packet_bytes,
+            tracker: self.tracker.clone(),
+            logger: self.logger.clone(),
+        };
 
         loop {
             tokio::select! {
                 result = listener.accept() => {
-                    match result {
-                        Ok((stream, addr)) => {
-                            self.logger.d(&format!("New connection from {}", addr.to_string().bright_magenta()));
-
-                            let session_manager = Arc::clone(&self.session_manager);
-                            let packet_handler = Arc::clone(&self.packet_handler);
-                            let message_service = Arc::clone(&self.message_service);
-                            let logger = self.logger.clone();
-                            let shutdown_token = shutdown_token.child_token();
-
-                            tokio::spawn(async move {
-                                if let Err(e) = handle_connection(
-                                    stream,
-                                    session_manager,
-                                    packet_handler,
-                                    message_service,
-                                    shutdown_token,
-                                    logger.clone(),
-                                ).await {
-                                    logger.e(&format!("Connection error: {}", e));
-                                }
-                            });
-                        }
-                        Err(e) => self.logger.e(&format!("Failed to accept connection: {}", e)),
-                    }
+                    self.accept(result, Transport::Tcp, &context, &shutdown_token);
+                }
+
+                result = accept_optional(ws_listener.as_ref()) => {
+                    self.accept(result, Transport::WebSocket, &context, &shutdown_token);
                 }
 
                 _ = shutdown_token.cancelled() => {
@@ -109,104 +293,456 @@ impl Server {
 
         self.logger.i("Server stopped accepting new connections");
     }
+
+    /// Waits for connections, deliveries and background tasks to finish, up to the shutdown deadline.
+    /// The shutdown token passed to `listen` must already be cancelled.
+    pub async fn drain(&self) {
+        self.tracker.close();
+
+        match tokio::time::timeout(self.shutdown_timeout, self.tracker.wait()).await {
+            Ok(()) => self.logger.i("All connections and deliveries finished"),
+            Err(_) => self.logger.w(&format!(
+                "Shutdown deadline passed with {} tasks still running",
+                self.tracker.len()
+            )),
+        }
+    }
+
+    fn accept(
+        &self,
+        result: std::io::Result<(TcpStream, SocketAddr)>,
+        transport: Transport,
+        context: &ConnectionContext,
+        shutdown_token: &CancellationToken,
+    ) {
+        let (stream, addr) = match result {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                self.logger.e(&format!("Failed to accept connection: {}", e));
+                return;
+            }
+        };
+
+        let connection_id = ConnectionId::next();
+
+        self.logger.d(&format!(
+            "New {} connection {} from {}",
+            transport.as_str(),
+            connection_id,
+            addr.to_string().bright_magenta()
+        ));
+
+        let Some(slot) = context.rate_limiter.try_connect(addr.ip()) else {
+            self.logger.w(&format!(
+                "Refusing connection from {}, too many open connections",
+                addr
+            ));
+            return;
+        };
+
+        let context = context.clone();
+        let shutdown_token = shutdown_token.child_token();
+
+        // Everything logged while serving the connection carries its id, services included.
+        context.tracker.clone().spawn(Logger::scope(connection_id, async move {
+            // Held for the lifetime of the connection, frees the per-IP slot on the way out.
+            let _slot = slot;
+            let logger = context.logger.clone();
+
+            let result = match context.tls_acceptor.clone() {
+                Some(acceptor) => match handshake(acceptor.accept(stream), &shutdown_token).await {
+                    Ok(tls_stream) => {
+                        serve_stream(
+                            tls_stream,
+                            addr,
+                            connection_id,
+                            transport,
+                            context,
+                            shutdown_token,
+                        )
+                        .await
+                    }
+                    Err(e) => {
+                        logger.d(&format!("TLS handshake with {} failed: {}", addr, e));
+                        return;
+                    }
+                },
+                None => {
+                    serve_stream(stream, addr, connection_id, transport, context, shutdown_token)
+                        .await
+                }
+            };
+
+            if let Err(e) = result {
+                logger.e(&format!("Connection error: {}", e));
+            }
+        }));
+    }
 }
 
-async fn handle_connection(
-    stream: TcpStream,
+#[derive(Clone, Copy)]
+enum Transport {
+    Tcp,
+    WebSocket,
+}
+
+impl Transport {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Transport::Tcp => "TCP",
+            Transport::WebSocket => "WebSocket",
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ConnectionContext {
     session_manager: Arc<SessionManager>,
     packet_handler: Arc<PacketHandler>,
+    auth_service: Arc<AuthService>,
     message_service: Arc<MessageService>,
-    shutdown_token: CancellationToken,
+    receipt_service: Arc<ReceiptService>,
+    presence_service: Arc<PresenceService>,
+    device_service: Arc<DeviceService>,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    tls_acceptor: Option<TlsAcceptor>,
+    /// The largest WebSocket message or frame a client may send.
+    max_packet_bytes: usize,
+    tracker: TaskTracker,
     logger: Logger,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let peer_addr = stream.peer_addr()?;
-    let (mut read_half, write_half) = tokio::io::split(stream);
+}
 
-    let temp_id = format!("{}", peer_addr).into_bytes();
+/// Gives up on a handshake that takes longer than HANDSHAKE_TIMEOUT or outlives the server.
+async fn handshake<T, E>(
+    future: impl Future<Output = Result<T, E>>,
+    shutdown_token: &CancellationToken,
+) -> io::Result<T>
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    tokio::select! {
+        result = tokio::time::timeout(HANDSHAKE_TIMEOUT, future) => match result {
+            Ok(result) => result.map_err(io::Error::other),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out")),
+        },
+
+        _ = shutdown_token.cancelled() => {
+            Err(io::Error::new(io::ErrorKind::Interrupted, "server is shutting down"))
+        }
+    }
+}
 
-    session_manager
-        .add_session(temp_id.clone(), Session::new(temp_id.clone(), write_half));
+async fn accept_optional(
+    listener: Option<&TcpListener>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
 
-    let mut current_user: Option<Vec<u8>> = Some(temp_id.clone());
-    let timeout_duration = tokio::time::Duration::from_secs(90);
+async fn serve_stream<S>(
+    stream: S,
+    peer_addr: SocketAddr,
+    connection_id: ConnectionId,
+    transport: Transport,
+    context: ConnectionContext,
+    shutdown_token: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match transport {
+        Transport::Tcp => {
+            let (read_half, write_half) = tokio::io::split(stream);
+
+            handle_connection(
+                StreamSource(read_half),
+                StreamSink(write_half),
+                peer_addr,
+                connection_id,
+                context,
+                shutdown_token,
+            )
+            .await
+        }
+        Transport::WebSocket => {
+            // The defaults allow 64 MiB messages, buffered before anyone has logged in.
+            let ws_config = WebSocketConfig::default()
+                .max_message_size(Some(context.max_packet_bytes))
+                .max_frame_size(Some(context.max_packet_bytes));
+
+            let (sink, source) = handshake(
+                tokio_tungstenite::accept_async_with_config(stream, Some(ws_config)),
+                &shutdown_token,
+            )
+            .await?
+            .split();
+
+            handle_connection(
+                WsSource(source),
+                WsSink(sink),
+                peer_addr,
+                connection_id,
+                context,
+                shutdown_token,
+            )
+            .await
+        }
+    }
+}
+
+async fn handle_connection(
+    mut source: impl PacketSource,
+    sink: impl PacketSink,
+    peer_addr: SocketAddr,
+    connection_id: ConnectionId,
+    context: ConnectionContext,
+    shutdown_token: CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ConnectionContext {
+        session_manager,
+        packet_handler,
+        auth_service,
+        message_service,
+        receipt_service,
+        presence_service,
+        device_service,
+        rate_limiter,
+        metrics,
+        tracker,
+        mut logger,
+        ..
+    } = context;
+
+    let temp_id = SessionId::new(format!("{}", peer_addr).into_bytes(), Vec::new());
+
+    // Cancelled by the idle reaper, by a newer login of the same device, or whoever else wants this connection gone.
+    let close_token = CancellationToken::new();
+
+    session_manager.add_session(Session::new(
+        temp_id.clone(),
+        connection_id,
+        sink,
+        close_token.clone(),
+        &tracker,
+    ));
+
+    let mut current_user: Option<SessionId> = Some(temp_id.clone());
+    // Throttled packets in a row, a client that keeps ignoring RateLimited gets disconnected.
+    let mut violations = 0;
 
     loop {
         tokio::select! {
-            result = tokio::time::timeout(timeout_duration, RawPacket::read_from(&mut read_half)) => {
+            result = source.read_packet() => {
                 match result {
-                    Ok(Ok(raw)) => {
-                        match Packet::from_raw(raw) {
-                            Ok(Packet::Ping) => {
+                    Ok(raw) => {
+                        let session = current_user.clone().unwrap_or_else(|| temp_id.clone());
+
+                        session_manager.touch(&session);
+
+                        // Echoed in Packet::Error so the client can match it to the request that failed.
+                        let (packet_id, request_id) = (raw.id, raw.request_id);
+
+                        // Charged before decoding, so malformed frames are throttled like any other.
+                        if let Err(retry_after) = rate_limiter.check_frame(peer_addr.ip()) {
+                            violations += 1;
+
+                            if violations > rate_limiter.max_violations() {
+                                logger.w(&format!("Disconnecting {} for ignoring rate limits", peer_addr));
+                                break;
+                            }
+
+                            send_rate_limited(&session_manager, &session, packet_id, retry_after).await;
+
+                            continue;
+                        }
+
+                        let packet = match Packet::from_raw(raw) {
+                            Ok(packet) => packet,
+                            Err(e) => {
+                                let error = ServerError::MalformedPacket(format!("{:?}", e));
+
+                                reply_error(&session_manager, &logger, &session, request_id, packet_id, &error).await;
+
+                                continue;
+                            }
+                        };
+
+                        metrics.packet_received(packet.get_id());
+
+                        let public_key = (session != temp_id).then_some(session.public_key.as_slice());
+
+                        if let Err(retry_after) = rate_limiter.check(peer_addr.ip(), public_key, &packet) {
+                            violations += 1;
+
+                            if violations > rate_limiter.max_violations() {
+                                logger.w(&format!("Disconnecting {} for ignoring rate limits", peer_addr));
+                                break;
+                            }
+
+                            send_rate_limited(&session_manager, &session, packet.get_id(), retry_after).await;
+
+                            continue;
+                        }
+
+                        violations = 0;
+
+                        match packet {
+                            Packet::Ping => {
                                 if let Some(ref user) = current_user {
-                                    let _ = session_manager.send_to_user(user, Packet::Pong).await;
+                                    let _ = session_manager.send_to_device(user, Packet::Pong).await;
                                 }
                             }
 
-                            Ok(Packet::LoginRequest { ref public_key, signature }) => {
-                                let pk = public_key.clone();
-
-                                if let Err(e) = packet_handler.handle(
-                                    current_user.clone(),
-                                    Packet::LoginRequest {
-                                        public_key: public_key.clone(),
-                                        signature,
+                            // Answer to a server ping, touching the session above was all it needed.
+                            Packet::Pong => {}
+
+                            Packet::LoginRequest { public_key, signature, device_id } => {
+                                let logged_in = match packet_handler
+                                    .handle_login(&session, public_key, device_id, signature)
+                                    .await
+                                {
+                                    Ok(logged_in) => logged_in,
+                                    Err(e) => {
+                                        reply_error(&session_manager, &logger, &session, request_id, packet_id, &e).await;
+                                        None
                                     }
-                                ).await {
-                                    logger.e(&format!("Failed to handle login request: {}", e));
-                                }
+                                };
 
-                                session_manager.move_session(&temp_id, pk.clone()).await;
-                                current_user = Some(pk.clone());
+                                let Some(logged_in) = logged_in else {
+                                    continue;
+                                };
+                                let device_session = logged_in.session;
 
-                                tokio::spawn({
+                                current_user = Some(device_session.clone());
+                                logger = logger.with_user(&device_session.public_key);
+
+                                if let Err(e) = presence_service.user_connected(&device_session, logged_in.came_online).await {
+                                    logger.e(&format!("Failed to update presence: {}", e));
+                                }
+
+                                // A spawned task starts without the connection's log scope.
+                                tracker.spawn(Logger::scope(connection_id, {
                                     let message_service = Arc::clone(&message_service);
-                                    let pubkey = pk.clone();
+                                    let receipt_service = Arc::clone(&receipt_service);
                                     let logger = logger.clone();
                                     async move {
                                         tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                                        if let Err(e) = message_service.deliver_pending_messages(&pubkey).await {
+                                        if let Err(e) = message_service.deliver_pending_messages(&device_session).await {
                                             logger.e(&format!("Failed to deliver pending messages: {}", e));
                                         }
+                                        if let Err(e) = receipt_service.deliver_pending_receipts(&device_session).await {
+                                            logger.e(&format!("Failed to deliver pending receipts: {}", e));
+                                        }
                                     }
-                                });
+                                }));
 
                                 continue;
                             }
 
-                            Ok(packet) => {
+                            packet => {
                                 if let Err(e) = packet_handler.handle(current_user.clone(), packet).await {
-                                    logger.e(&format!("Failed to handle packet: {}", e));
+                                    reply_error(&session_manager, &logger, &session, request_id, packet_id, &e).await;
                                 }
                             }
-
-                            Err(e) => logger.w(&format!("Received invalid packet: {:?}", e)),
                         }
                     }
-                    Ok(Err(e)) => {
+                    Err(e) => {
                         logger.d(&format!("Connection read error: {}", e));
                         break;
                     }
-                    Err(_) => {
-                        logger.d(&format!("Connection timeout after {} seconds of inactivity", timeout_duration.as_secs()));
-                        break;
-                    }
                 }
             }
 
+            _ = close_token.cancelled() => {
+                logger.d(&format!("Closing connection from {}", peer_addr));
+                break;
+            }
+
             _ = shutdown_token.cancelled() => {
                 logger.i("Connection closing due to server shutdown");
+
+                if let Some(ref user) = current_user {
+                    let _ = session_manager.send_to_device(user, Packet::GoingAway).await;
+                }
+
                 break;
             }
         }
     }
 
+    // A challenge the connection asked for and never used would otherwise wait out its TTL.
+    auth_service.discard_challenge(&temp_id).await;
+
     if let Some(user_id) = current_user {
-        session_manager.remove_session(&user_id);
+        // A newer login of the same device owns the session now, and the device is still online.
+        let removal = session_manager.remove_session(&user_id, connection_id);
+
+        if removal != Removal::Kept && user_id != temp_id {
+            if let Err(e) = presence_service.user_disconnected(&user_id, removal == Removal::WentOffline).await {
+                logger.e(&format!("Failed to update presence: {}", e));
+            }
+            if let Err(e) = device_service.touch(&user_id).await {
+                logger.e(&format!("Failed to update device: {}", e));
+            }
+        }
+
         logger.d(&format!(
             "User disconnected: {}",
-            hex::encode(&user_id[..4])
+            hex::encode(&user_id.public_key[..4])
         ));
     }
 
     Ok(())
 }
+
+/// Logs a failed request and tells the client, so it is never left waiting for a reply.
+async fn reply_error(
+    session_manager: &SessionManager,
+    logger: &Logger,
+    session: &SessionId,
+    request_id: u32,
+    packet_id: u8,
+    error: &ServerError,
+) {
+    let code = error.code();
+
+    match code {
+        ErrorCode::Internal => {
+            logger.e(&format!("Failed to handle packet {:02X}: {}", packet_id, error))
+        }
+        ErrorCode::MalformedPacket => logger.w(&format!("Received invalid packet: {}", error)),
+        _ => logger.d(&format!("Rejected packet {:02X}: {}", packet_id, error)),
+    }
+
+    let _ = session_manager
+        .send_to_device(
+            session,
+            Packet::Error {
+                request_id,
+                packet_id,
+                code: code as u16,
+            },
+        )
+        .await;
+}
+
+/// Tells the client when it may retry a throttled packet.
+async fn send_rate_limited(
+    session_manager: &SessionManager,
+    session: &SessionId,
+    packet_id: u8,
+    retry_after: Duration,
+) {
+    let _ = session_manager
+        .send_to_device(
+            session,
+            Packet::RateLimited {
+                packet_id,
+                retry_after_ms: retry_after.as_millis().try_into().unwrap_or(u32::MAX),
+            },
+        )
+        .await;
+}
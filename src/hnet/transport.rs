@@ -0,0 +1,82 @@
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use hnet_protocol::RawPacket;
+use std::future::Future;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+
+pub trait PacketSource: Send {
+    fn read_packet(&mut self) -> impl Future<Output = io::Result<RawPacket>> + Send;
+}
+
+pub trait PacketSink: Send + 'static {
+    fn write_packet(&mut self, raw: &RawPacket) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+pub struct StreamSource<S>(pub ReadHalf<S>);
+
+pub struct StreamSink<S>(pub WriteHalf<S>);
+
+impl<S> PacketSource for StreamSource<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn read_packet(&mut self) -> io::Result<RawPacket> {
+        RawPacket::read_from(&mut self.0).await
+    }
+}
+
+impl<S> PacketSink for StreamSink<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn write_packet(&mut self, raw: &RawPacket) -> io::Result<()> {
+        raw.write_to(&mut self.0).await?;
+        self.0.flush().await
+    }
+}
+
+pub struct WsSource<S>(pub SplitStream<WebSocketStream<S>>);
+
+pub struct WsSink<S>(pub SplitSink<WebSocketStream<S>, Message>);
+
+impl<S> PacketSource for WsSource<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    async fn read_packet(&mut self) -> io::Result<RawPacket> {
+        loop {
+            match self.0.next().await {
+                Some(Ok(Message::Binary(data))) => {
+                    return RawPacket::read_from(&mut &data[..]).await;
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "WebSocket closed",
+                    ));
+                }
+                // Control frames are answered by tungstenite itself, text frames are not part of hnet.
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(io::Error::other(e)),
+            }
+        }
+    }
+}
+
+impl<S> PacketSink for WsSink<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    async fn write_packet(&mut self, raw: &RawPacket) -> io::Result<()> {
+        let mut frame = Vec::new();
+        raw.write_to(&mut frame).await?;
+
+        self.0
+            .send(Message::binary(frame))
+            .await
+            .map_err(io::Error::other)
+    }
+}
@@ -1,36 +1,121 @@
-use chrono::Local;
+use crate::logging::output;
+use crate::session::ConnectionId;
+use chrono::{Local, SecondsFormat, Utc};
 use colored::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+tokio::task_local! {
+    // Set around work done on behalf of a connection, so code that never sees the id still logs it.
+    static CONNECTION: ConnectionId;
+}
+
+static LEVELS: RwLock<Levels> = RwLock::new(Levels {
+    default: LogLevel::DEFAULT,
+    modules: BTreeMap::new(),
+});
+
+struct Levels {
+    default: LogLevel,
+    // Keyed by module name in upper case, as passed to `Logger::new`
+    modules: BTreeMap<String, LogLevel>,
+}
 
 #[derive(Clone)]
 pub struct Logger {
     module: String,
+    user: Option<String>,
 }
 
-enum Level {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
     Error,
     Warn,
     Info,
     Debug,
 }
 
-impl Level {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl LogLevel {
+    pub const DEFAULT: LogLevel = if cfg!(debug_assertions) {
+        LogLevel::Debug
+    } else {
+        LogLevel::Info
+    };
+
     pub fn as_str(&self) -> ColoredString {
         match self {
-            Level::Error => "E".red().bold(),
-            Level::Warn => "W".yellow().bold(),
-            Level::Info => "I".white().bold(),
-            Level::Debug => "D".bright_black().bold(),
+            LogLevel::Error => "E".red().bold(),
+            LogLevel::Warn => "W".yellow().bold(),
+            LogLevel::Info => "I".white().bold(),
+            LogLevel::Debug => "D".bright_black().bold(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
         }
     }
 }
 
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'static str,
+    module: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connection: Option<ConnectionId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<&'a str>,
+    message: &'a str,
+}
+
 impl Logger {
     pub fn new(module: &'static str) -> Logger {
         Logger {
             module: module.to_string(),
+            user: None,
+        }
+    }
+
+    /// Tags every line with a short hash of the user's key, the key itself never reaches the logs.
+    pub fn with_user(&self, public_key: &[u8]) -> Logger {
+        Logger {
+            user: Some(hex::encode(&Sha256::digest(public_key)[..8])),
+            ..self.clone()
         }
     }
 
+    /// Runs `future` with every line logged inside it tagged with `connection`,
+    /// whichever logger writes it. The only way a line gets a connection.
+    pub async fn scope<F: Future>(connection: ConnectionId, future: F) -> F::Output {
+        CONNECTION.scope(connection, future).await
+    }
+
+    /// Replaces the default level and the per-module overrides, takes effect on the next line.
+    pub fn set_levels(default: LogLevel, modules: &BTreeMap<String, LogLevel>) {
+        let mut levels = LEVELS.write().unwrap();
+
+        levels.default = default;
+        levels.modules = modules
+            .iter()
+            .map(|(module, level)| (module.to_uppercase(), *level))
+            .collect();
+    }
+
     pub fn log_err<T, E: std::fmt::Display>(
         &self,
         result: Result<T, E>,
@@ -46,31 +131,84 @@ impl Logger {
     }
 
     pub fn i(&self, args: &str) {
-        self.print(Level::Info, args);
+        self.print(LogLevel::Info, args);
     }
 
     pub fn e(&self, args: &str) {
-        self.print(Level::Error, args);
+        self.print(LogLevel::Error, args);
     }
 
     pub fn w(&self, args: &str) {
-        self.print(Level::Warn, args);
+        self.print(LogLevel::Warn, args);
     }
 
     pub fn d(&self, args: &str) {
-        #[cfg(debug_assertions)]
-        {
-            self.print(Level::Debug, &args.bright_black().to_string());
+        self.print(LogLevel::Debug, args);
+    }
+
+    fn connection(&self) -> Option<ConnectionId> {
+        CONNECTION.try_with(|connection| *connection).ok()
+    }
+
+    fn enabled(&self, level: LogLevel) -> bool {
+        let levels = LEVELS.read().unwrap();
+        let max_level = levels
+            .modules
+            .get(&self.module)
+            .copied()
+            .unwrap_or(levels.default);
+
+        level <= max_level
+    }
+
+    fn print(&self, level: LogLevel, args: &str) {
+        if !self.enabled(level) {
+            return;
         }
+
+        let line = match output::format() {
+            LogFormat::Text => self.text_line(level, args),
+            LogFormat::Json => self.json_line(level, args),
+        };
+
+        output::write_line(&line);
     }
 
-    fn print(&self, level: Level, args: &str) {
-        println!(
-            "[{}] [{}] [{}] {}",
+    fn text_line(&self, level: LogLevel, args: &str) -> String {
+        let mut context = String::new();
+
+        if let Some(connection) = self.connection() {
+            context.push_str(&format!(" [{}]", connection));
+        }
+        if let Some(user) = &self.user {
+            context.push_str(&format!(" [{}]", user));
+        }
+
+        let args = match level {
+            LogLevel::Debug => args.bright_black().to_string(),
+            _ => args.to_string(),
+        };
+
+        format!(
+            "[{}] [{}] [{}]{} {}",
             Local::now().format("%H:%M:%S%.3f"),
             level.as_str(),
             self.module.as_str().cyan().bold(),
+            context,
             args
-        );
+        )
+    }
+
+    fn json_line(&self, level: LogLevel, args: &str) -> String {
+        let record = JsonRecord {
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            level: level.name(),
+            module: &self.module,
+            connection: self.connection(),
+            user: self.user.as_deref(),
+            message: args,
+        };
+
+        serde_json::to_string(&record).unwrap_or_default()
     }
 }
@@ -1,3 +1,5 @@
 mod logger;
+mod output;
 
-pub use logger::Logger;
+pub use logger::{LogFormat, LogLevel, Logger};
+pub use output::init;
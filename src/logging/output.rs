@@ -0,0 +1,193 @@
+use crate::logging::LogFormat;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+// Lines waiting for the writer thread. Past this they are dropped rather than stall the caller.
+const QUEUE_SIZE: usize = 8192;
+
+static OUTPUT: OnceLock<Output> = OnceLock::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+struct Output {
+    format: LogFormat,
+    queue: SyncSender<Message>,
+}
+
+enum Message {
+    Line(String),
+    // Answered once every line queued before it is written.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Returned by `init`, dropping it waits until every line logged so far is written.
+#[must_use]
+pub struct FlushGuard(());
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        let Some(output) = OUTPUT.get() else {
+            return;
+        };
+
+        let (done, flushed) = mpsc::channel();
+
+        if output.queue.send(Message::Flush(done)).is_ok() {
+            let _ = flushed.recv();
+        }
+    }
+}
+
+/// Appends to `path` and moves it aside as `path.1`, `path.2`, ... once it grows past `max_bytes`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64, max_files: u32) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let written = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            written,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+
+        if self.written > 0 && self.written + len > self.max_bytes {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.written += len;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        // The oldest file falls off the end by being overwritten.
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+
+            if from.exists() {
+                std::fs::rename(from, self.rotated_path(index + 1))?;
+            }
+        }
+
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+}
+
+/// Switches the output format and, when a path is given, moves output from stdout into that file.
+/// From here on lines are written by a dedicated thread, logging never waits on the disk.
+pub fn init(
+    format: LogFormat,
+    file: Option<&Path>,
+    max_file_bytes: u64,
+    max_files: u32,
+) -> io::Result<FlushGuard> {
+    let file = file
+        .map(|path| RotatingFile::open(path, max_file_bytes, max_files))
+        .transpose()?;
+
+    // Escape codes only make sense on a terminal.
+    if format == LogFormat::Json || file.is_some() {
+        colored::control::set_override(false);
+    }
+
+    let (queue, lines) = mpsc::sync_channel(QUEUE_SIZE);
+
+    OUTPUT
+        .set(Output { format, queue })
+        .map_err(|_| io::Error::other("Log output is already set up"))?;
+
+    thread::Builder::new()
+        .name("log-writer".to_string())
+        .spawn(move || run_writer(lines, file))?;
+
+    Ok(FlushGuard(()))
+}
+
+pub(super) fn format() -> LogFormat {
+    OUTPUT.get().map_or(LogFormat::Text, |output| output.format)
+}
+
+pub(super) fn write_line(line: &str) {
+    let Some(output) = OUTPUT.get() else {
+        println!("{}", line);
+        return;
+    };
+
+    match output.queue.try_send(Message::Line(line.to_string())) {
+        Ok(()) => (),
+        Err(TrySendError::Full(_)) => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        // The writer thread is gone, nothing else will print this.
+        Err(TrySendError::Disconnected(_)) => eprintln!("{}", line),
+    }
+}
+
+fn run_writer(lines: Receiver<Message>, mut file: Option<RotatingFile>) {
+    for message in lines {
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+
+        if dropped > 0 {
+            let notice = format!("{} log lines dropped, the log writer fell behind", dropped);
+            write(&mut file, &notice);
+        }
+
+        match message {
+            Message::Line(line) => write(&mut file, &line),
+            Message::Flush(done) => {
+                if let Some(file) = &mut file {
+                    let _ = file.file.flush();
+                }
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+fn write(file: &mut Option<RotatingFile>, line: &str) {
+    match file {
+        Some(file) => {
+            if let Err(e) = file.write_line(line) {
+                eprintln!("Failed to write log file: {}", e);
+                eprintln!("{}", line);
+            }
+        }
+        None => println!("{}", line),
+    }
+}
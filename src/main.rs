@@ -1,29 +1,95 @@
+mod config;
 mod db;
+mod error;
 mod handlers;
 mod hnet;
 mod logging;
+mod metrics;
 mod services;
 mod session;
 
+use clap::Parser;
+use config::{Cli, Config, StorageBackend};
+use db::{MemoryStorage, PgStorage, Storage};
 use hnet::server::Server;
 use logging::Logger;
+use metrics::Metrics;
+use std::sync::Arc;
 use tokio::signal;
 use tokio_util::sync::CancellationToken;
 
-async fn init(logger: Logger) -> Result<(), Box<dyn std::error::Error>> {
-    let database_url =
-        logger.log_err(std::env::var("DATABASE_URL"), "Database url not set in env")?;
+async fn init(logger: Logger, cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let config = logger.log_err(Config::load(&cli), "Invalid configuration")?;
 
-    logger.d("Create DB pool");
+    Logger::set_levels(config.log.level, &config.log.modules);
 
-    let db_pool = logger.log_err(
-        db::create_pool(&database_url).await,
-        "Error creating database pool",
+    // Held until the server stops, so the last lines reach the log before the process exits.
+    let _flush_guard = logger.log_err(
+        logging::init(
+            config.log.format,
+            config.log.file.as_deref(),
+            config.log.max_file_bytes,
+            config.log.max_files,
+        ),
+        "Cannot open log file",
     )?;
 
-    logger.d("DB Pool created");
+    #[cfg(unix)]
+    tokio::spawn(reload_log_levels(cli.clone(), logger.clone()));
+
+    let metrics = Arc::new(Metrics::new());
+
+    let storage: Arc<dyn Storage> = match config.database.storage {
+        StorageBackend::Memory => {
+            if cli.migrate_only {
+                logger.e("--migrate-only needs a database, not in-memory storage");
+                return Err("Nothing to migrate".into());
+            }
+
+            logger.w("Using in-memory storage, nothing will survive a restart");
+            Arc::new(MemoryStorage::new())
+        }
+        StorageBackend::Postgres => {
+            let database_url = config.database.url.as_deref().unwrap_or_default();
+
+            logger.d("Create DB pool");
+
+            let db_pool = logger.log_err(
+                db::create_pool(database_url, config.database.max_connections).await,
+                "Error creating database pool",
+            )?;
+
+            logger.d("DB Pool created");
+
+            logger.log_err(
+                db::run_migrations(&db_pool).await,
+                "Error applying database migrations",
+            )?;
+
+            logger.i("Database schema is up to date");
+
+            if cli.migrate_only {
+                return Ok(());
+            }
+
+            Arc::new(PgStorage::new(db_pool, Arc::clone(&metrics)))
+        }
+    };
+
+    let mut server = Server::new(&config, storage, metrics);
+
+    if let (Some(cert_path), Some(key_path)) = (
+        &config.server.tls_cert_path,
+        &config.server.tls_key_path,
+    ) {
+        let tls_acceptor = logger.log_err(
+            hnet::tls::load_acceptor(cert_path, key_path),
+            "Error loading TLS certificate",
+        )?;
+
+        server = server.with_tls(tls_acceptor);
+    }
 
-    let server = Server::new("127.0.0.1".to_string(), 8123, db_pool);
     let shutdown_token = CancellationToken::new();
 
     tokio::select! {
@@ -32,14 +98,36 @@ async fn init(logger: Logger) -> Result<(), Box<dyn std::error::Error>> {
         }
         _ = shutdown_signal() => {
             logger.i("Received shutdown signal, stopping server...");
-            shutdown_token.cancel();
-            tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
         }
     }
 
+    shutdown_token.cancel();
+    server.drain().await;
+
     Ok(())
 }
 
+/// Re-reads log levels from the configuration on SIGHUP, anything else still needs a restart.
+#[cfg(unix)]
+async fn reload_log_levels(cli: Cli, logger: Logger) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+        logger.w("Cannot listen for SIGHUP, log levels only change on restart");
+        return;
+    };
+
+    while hangup.recv().await.is_some() {
+        match Config::load(&cli) {
+            Ok(config) => {
+                Logger::set_levels(config.log.level, &config.log.modules);
+                logger.i("Reloaded log levels");
+            }
+            Err(e) => logger.e(&format!("Keeping current log levels: {}", e)),
+        }
+    }
+}
+
 async fn shutdown_signal() {
     #[cfg(unix)]
     {
@@ -64,6 +152,8 @@ async fn shutdown_signal() {
 async fn main() {
     dotenvy::dotenv().ok();
 
+    let cli = Cli::parse();
+
     let logger = Logger::new("MAIN");
 
     #[cfg(debug_assertions)]
@@ -73,7 +163,7 @@ async fn main() {
 
     logger.i("Starting server");
 
-    if let Err(_) = init(logger.clone()).await {
+    if init(logger.clone(), cli).await.is_err() {
         std::process::exit(1);
     }
 }
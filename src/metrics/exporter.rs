@@ -0,0 +1,131 @@
+use crate::logging::Logger;
+use crate::metrics::Metrics;
+use crate::session::SessionManager;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves `GET /metrics` over plain HTTP/1.1, one request per connection.
+pub struct MetricsExporter {
+    metrics: Arc<Metrics>,
+    session_manager: Arc<SessionManager>,
+    logger: Logger,
+}
+
+impl MetricsExporter {
+    pub fn new(metrics: Arc<Metrics>, session_manager: Arc<SessionManager>) -> Self {
+        Self {
+            metrics,
+            session_manager,
+            logger: Logger::new("METRICS"),
+        }
+    }
+
+    /// Requests are served on `tracker`, so shutdown waits for a scrape in flight.
+    pub async fn run(
+        self: Arc<Self>,
+        listener: TcpListener,
+        tracker: TaskTracker,
+        shutdown_token: CancellationToken,
+    ) {
+        loop {
+            tokio::select! {
+                result = listener.accept() => {
+                    let stream = match result {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            self.logger.e(&format!("Failed to accept metrics connection: {}", e));
+                            continue;
+                        }
+                    };
+
+                    let exporter = Arc::clone(&self);
+
+                    tracker.spawn(async move {
+                        // A scraper that stalls past the timeout is just dropped.
+                        if let Ok(Err(e)) = tokio::time::timeout(REQUEST_TIMEOUT, exporter.respond(stream)).await {
+                            exporter.logger.d(&format!("Metrics request failed: {}", e));
+                        }
+                    });
+                }
+
+                _ = shutdown_token.cancelled() => break,
+            }
+        }
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            if request.len() >= MAX_REQUEST_BYTES {
+                return Ok(());
+            }
+
+            let read = stream.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok(());
+            }
+
+            request.extend_from_slice(&buffer[..read]);
+        }
+
+        let request = String::from_utf8_lossy(&request);
+        let mut request_line = request
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .split_whitespace();
+        let method = request_line.next().unwrap_or_default();
+        let path = request_line
+            .next()
+            .unwrap_or_default()
+            .split('?')
+            .next()
+            .unwrap_or_default();
+
+        let (status, content_type, body) = match (method, path) {
+            ("GET", "/metrics") => {
+                self.refresh();
+
+                match self.metrics.encode() {
+                    Ok(body) => ("200 OK", prometheus::TEXT_FORMAT, body),
+                    Err(e) => {
+                        self.logger.e(&format!("Failed to encode metrics: {}", e));
+                        (
+                            "500 Internal Server Error",
+                            "text/plain",
+                            b"encoding failed\n".to_vec(),
+                        )
+                    }
+                }
+            }
+            _ => ("404 Not Found", "text/plain", b"not found\n".to_vec()),
+        };
+
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            content_type,
+            body.len()
+        );
+
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        stream.shutdown().await
+    }
+
+    // Gauges that are cheaper to read on demand than to keep in step with every change. The pending
+    // message count needs the database and is kept up to date by maintenance instead.
+    fn refresh(&self) {
+        let (active, authenticated) = self.session_manager.session_counts();
+        self.metrics.set_sessions(active, authenticated);
+    }
+}
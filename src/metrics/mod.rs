@@ -0,0 +1,5 @@
+mod exporter;
+mod registry;
+
+pub use exporter::MetricsExporter;
+pub use registry::Metrics;
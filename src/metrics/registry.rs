@@ -0,0 +1,125 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::time::Duration;
+
+/// Everything the server exposes for scraping. Recording is cheap and always on,
+/// the HTTP listener is what makes it optional.
+pub struct Metrics {
+    registry: Registry,
+    sessions_active: IntGauge,
+    sessions_authenticated: IntGauge,
+    packets_received: IntCounterVec,
+    deliveries: IntCounterVec,
+    pending_messages: IntGauge,
+    db_query_seconds: HistogramVec,
+    auth_failures: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("hnet".to_string()), None).expect("metrics prefix is valid");
+
+        let sessions_active =
+            IntGauge::new("sessions_active", "Open connections, logged in or not")
+                .expect("metric definition is valid");
+        let sessions_authenticated =
+            IntGauge::new("sessions_authenticated", "Connections that completed login")
+                .expect("metric definition is valid");
+        let packets_received = IntCounterVec::new(
+            Opts::new("packets_received_total", "Decoded packets by packet id"),
+            &["packet_id"],
+        )
+        .expect("metric definition is valid");
+        let deliveries = IntCounterVec::new(
+            Opts::new(
+                "deliveries_total",
+                "Messages stored per recipient device, by whether a live session got them right away",
+            ),
+            &["path"],
+        )
+        .expect("metric definition is valid");
+        let pending_messages = IntGauge::new(
+            "pending_messages",
+            "Rows in pending_messages at the last maintenance run",
+        )
+        .expect("metric definition is valid");
+        let db_query_seconds = HistogramVec::new(
+            HistogramOpts::new("db_query_duration_seconds", "Database query latency").buckets(
+                vec![
+                    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+                ],
+            ),
+            &["query"],
+        )
+        .expect("metric definition is valid");
+        let auth_failures = IntCounterVec::new(
+            Opts::new("auth_failures_total", "Rejected login attempts by reason"),
+            &["reason"],
+        )
+        .expect("metric definition is valid");
+
+        for collector in [
+            Box::new(sessions_active.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(sessions_authenticated.clone()),
+            Box::new(packets_received.clone()),
+            Box::new(deliveries.clone()),
+            Box::new(pending_messages.clone()),
+            Box::new(db_query_seconds.clone()),
+            Box::new(auth_failures.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("metric names are unique");
+        }
+
+        Self {
+            registry,
+            sessions_active,
+            sessions_authenticated,
+            packets_received,
+            deliveries,
+            pending_messages,
+            db_query_seconds,
+            auth_failures,
+        }
+    }
+
+    pub fn set_sessions(&self, active: usize, authenticated: usize) {
+        self.sessions_active.set(active as i64);
+        self.sessions_authenticated.set(authenticated as i64);
+    }
+
+    pub fn set_pending_messages(&self, count: i64) {
+        self.pending_messages.set(count);
+    }
+
+    pub fn packet_received(&self, packet_id: u8) {
+        self.packets_received
+            .with_label_values(&[format!("{:02X}", packet_id)])
+            .inc();
+    }
+
+    pub fn delivery(&self, live: bool) {
+        let path = if live { "live" } else { "queued" };
+        self.deliveries.with_label_values(&[path]).inc();
+    }
+
+    pub fn db_query(&self, query: &'static str, elapsed: Duration) {
+        self.db_query_seconds
+            .with_label_values(&[query])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn auth_failure(&self, reason: &'static str) {
+        self.auth_failures.with_label_values(&[reason]).inc();
+    }
+
+    /// Text exposition format, ready to be served as is.
+    pub fn encode(&self) -> Result<Vec<u8>, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
@@ -1,49 +1,142 @@
-use crate::db::models::UserProfile;
-use crate::session::SessionManager;
-use sqlx::PgPool;
+use crate::db::Storage;
+use crate::error::{ServerError, ServerResult};
+use crate::logging::Logger;
+use crate::metrics::Metrics;
+use crate::services::DeviceService;
+use crate::session::{SessionId, SessionManager};
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
+const CHALLENGE_TTL: Duration = Duration::from_secs(60);
+const MAX_PENDING_CHALLENGES: usize = 10_000;
+const MAX_DEVICE_ID_LEN: usize = 64;
+
+struct PendingChallenge {
+    public_key: Vec<u8>,
+    challenge: Vec<u8>,
+    issued_at: Instant,
+}
+
+impl PendingChallenge {
+    fn is_expired(&self) -> bool {
+        self.issued_at.elapsed() >= CHALLENGE_TTL
+    }
+}
+
+struct Challenges {
+    pending: HashMap<SessionId, PendingChallenge>,
+    // Expired entries are swept at most once per TTL, not on every request.
+    purged_at: Instant,
+}
+
+impl Challenges {
+    fn purge_expired(&mut self) {
+        self.pending.retain(|_, pending| !pending.is_expired());
+        self.purged_at = Instant::now();
+    }
+}
+
+/// A login that went through.
+pub struct LoggedIn {
+    pub session: SessionId,
+    pub profile_exists: bool,
+    /// No other device of the user was logged in.
+    pub came_online: bool,
+}
+
 pub struct AuthService {
     session_manager: Arc<SessionManager>,
-    db_pool: PgPool,
-    challenges: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
+    storage: Arc<dyn Storage>,
+    device_service: Arc<DeviceService>,
+    metrics: Arc<Metrics>,
+    logger: Logger,
+    // Keyed by the session of the connection that asked, so nobody can replace another connection's challenge.
+    // A connection holds at most one and drops it on disconnect, so the connection limit per address
+    // also bounds the challenges per address.
+    challenges: Arc<Mutex<Challenges>>,
 }
 
 impl AuthService {
-    pub fn new(session_manager: Arc<SessionManager>, db_pool: PgPool) -> Self {
+    pub fn new(
+        session_manager: Arc<SessionManager>,
+        storage: Arc<dyn Storage>,
+        device_service: Arc<DeviceService>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         Self {
             session_manager,
-            db_pool,
-            challenges: Arc::new(Mutex::new(HashMap::new())),
+            storage,
+            device_service,
+            metrics,
+            logger: Logger::new("AUTH"),
+            challenges: Arc::new(Mutex::new(Challenges {
+                pending: HashMap::new(),
+                purged_at: Instant::now(),
+            })),
         }
     }
 
-    pub async fn generate_challenge(&self, public_key: Vec<u8>) -> Vec<u8> {
+    pub async fn generate_challenge(
+        &self,
+        session: SessionId,
+        public_key: Vec<u8>,
+    ) -> Option<Vec<u8>> {
         use rand::RngCore;
 
+        let mut challenges = self.challenges.lock().await;
+
+        let full = |challenges: &Challenges| {
+            challenges.pending.len() >= MAX_PENDING_CHALLENGES
+                && !challenges.pending.contains_key(&session)
+        };
+
+        if challenges.purged_at.elapsed() >= CHALLENGE_TTL || full(&challenges) {
+            challenges.purge_expired();
+        }
+        if full(&challenges) {
+            return None;
+        }
+
         let mut challenge = vec![0u8; 32];
         rand::rngs::OsRng.fill_bytes(&mut challenge);
 
-        self.challenges
-            .lock()
-            .await
-            .insert(public_key, challenge.clone());
+        challenges.pending.insert(
+            session,
+            PendingChallenge {
+                public_key,
+                challenge: challenge.clone(),
+                issued_at: Instant::now(),
+            },
+        );
 
-        challenge
+        Some(challenge)
+    }
+
+    /// Forgets the challenge of a connection that closed without logging in.
+    pub async fn discard_challenge(&self, session: &SessionId) {
+        self.challenges.lock().await.pending.remove(session);
     }
 
     pub async fn verify_login(
         &self,
+        session: &SessionId,
         public_key: &[u8],
+        device_id: &[u8],
         signature: &[u8],
-    ) -> Result<(bool, bool), Box<dyn std::error::Error>> {
-        let challenge = self.challenges.lock().await.remove(public_key);
+    ) -> ServerResult<Option<LoggedIn>> {
+        let pending = self.challenges.lock().await.pending.remove(session);
+
+        if device_id.is_empty() || device_id.len() > MAX_DEVICE_ID_LEN {
+            return Ok(self.reject("device_id"));
+        }
 
-        let challenge = match challenge {
-            Some(c) => c,
-            None => return Ok((false, false)),
+        let challenge = match pending {
+            Some(pending) if !pending.is_expired() && pending.public_key == public_key => {
+                pending.challenge
+            }
+            _ => return Ok(self.reject("challenge")),
         };
 
         use ed25519_dalek::{Signature, Verifier, VerifyingKey};
@@ -51,25 +144,211 @@ impl AuthService {
         let verifying_key = VerifyingKey::from_bytes(
             public_key
                 .try_into()
-                .map_err(|_| "Invalid public key length")?,
-        )?;
+                .map_err(|_| self.invalid("public_key", "public key length"))?,
+        )
+        .map_err(|_| self.invalid("public_key", "public key"))?;
 
         let signature = Signature::from_bytes(
             signature
                 .try_into()
-                .map_err(|_| "Invalid signature length")?,
+                .map_err(|_| self.invalid("signature", "signature length"))?,
         );
 
         match verifying_key.verify(&challenge, &signature) {
             Ok(_) => (),
-            Err(e) => return Ok((false, false)),
+            Err(_) => return Ok(self.reject("signature")),
         }
 
-        let profile = UserProfile::find_by_pubkey(&self.db_pool, public_key).await?;
+        let profile = self.storage.find_user(public_key).await?;
         let profile_exists = profile.is_some();
 
-        self.session_manager.set_authenticated(public_key).await;
+        self.device_service.register(public_key, device_id).await?;
+
+        // The session only takes the claimed key once the signature checked out.
+        let device_session = SessionId::new(public_key.to_vec(), device_id.to_vec());
+        let came_online = self.session_manager.log_in(session, device_session.clone());
+
+        Ok(Some(LoggedIn {
+            session: device_session,
+            profile_exists,
+            came_online,
+        }))
+    }
+
+    fn reject(&self, reason: &'static str) -> Option<LoggedIn> {
+        self.metrics.auth_failure(reason);
+        self.logger.w(&format!("Login rejected: {}", reason));
 
-        Ok((true, profile_exists))
+        None
+    }
+
+    // Counted like any rejection, but the client gets an error since the request itself is broken.
+    fn invalid(&self, reason: &'static str, argument: &'static str) -> ServerError {
+        self.metrics.auth_failure(reason);
+        self.logger.w(&format!("Login rejected: {}", reason));
+
+        ServerError::InvalidArgument(argument)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryStorage;
+    use crate::services::ReceiptService;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn service() -> AuthService {
+        let session_manager = Arc::new(SessionManager::new(16));
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let receipt_service = Arc::new(ReceiptService::new(
+            Arc::clone(&session_manager),
+            Arc::clone(&storage),
+        ));
+        let device_service = Arc::new(DeviceService::new(
+            Arc::clone(&session_manager),
+            Arc::clone(&storage),
+            receipt_service,
+        ));
+
+        AuthService::new(
+            session_manager,
+            storage,
+            device_service,
+            Arc::new(Metrics::new()),
+        )
+    }
+
+    fn connection(port: u16) -> SessionId {
+        SessionId::new(format!("127.0.0.1:{}", port).into_bytes(), Vec::new())
+    }
+
+    async fn login(
+        service: &AuthService,
+        session: &SessionId,
+        key: &SigningKey,
+        challenge: &[u8],
+    ) -> bool {
+        service
+            .verify_login(
+                session,
+                key.verifying_key().as_bytes(),
+                b"phone",
+                &key.sign(challenge).to_bytes(),
+            )
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    #[tokio::test]
+    async fn challenge_is_single_use() {
+        let service = service();
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let session = connection(1);
+
+        let challenge = service
+            .generate_challenge(session.clone(), key.verifying_key().to_bytes().to_vec())
+            .await
+            .unwrap();
+
+        assert!(login(&service, &session, &key, &challenge).await);
+        assert!(!login(&service, &session, &key, &challenge).await);
+    }
+
+    #[tokio::test]
+    async fn challenge_only_works_on_the_connection_that_asked() {
+        let service = service();
+        let key = SigningKey::from_bytes(&[7; 32]);
+
+        let challenge = service
+            .generate_challenge(connection(1), key.verifying_key().to_bytes().to_vec())
+            .await
+            .unwrap();
+
+        assert!(!login(&service, &connection(2), &key, &challenge).await);
+    }
+
+    #[tokio::test]
+    async fn expired_challenge_is_rejected_and_purged() {
+        let service = service();
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let session = connection(1);
+
+        let challenge = service
+            .generate_challenge(session.clone(), key.verifying_key().to_bytes().to_vec())
+            .await
+            .unwrap();
+        {
+            let mut challenges = service.challenges.lock().await;
+            challenges.pending.get_mut(&session).unwrap().issued_at -= CHALLENGE_TTL;
+            challenges.purged_at -= CHALLENGE_TTL;
+        }
+
+        // The next request past the sweep interval clears out the expired one.
+        service
+            .generate_challenge(connection(2), key.verifying_key().to_bytes().to_vec())
+            .await
+            .unwrap();
+        assert!(
+            !service
+                .challenges
+                .lock()
+                .await
+                .pending
+                .contains_key(&session)
+        );
+
+        assert!(!login(&service, &session, &key, &challenge).await);
+    }
+
+    #[tokio::test]
+    async fn malformed_key_counts_as_a_failed_login() {
+        let service = service();
+        let session = connection(1);
+
+        service
+            .generate_challenge(session.clone(), b"short".to_vec())
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            service
+                .verify_login(&session, b"short", b"phone", &[0; 64])
+                .await,
+            Err(ServerError::InvalidArgument(_))
+        ));
+
+        let encoded = String::from_utf8(service.metrics.encode().unwrap()).unwrap();
+        assert!(encoded.contains("auth_failures_total{reason=\"public_key\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn outstanding_challenges_are_capped() {
+        let service = service();
+
+        for port in 0..MAX_PENDING_CHALLENGES {
+            service
+                .generate_challenge(connection(port as u16), b"key".to_vec())
+                .await
+                .unwrap();
+        }
+
+        let extra = SessionId::new(b"another-host".to_vec(), Vec::new());
+        assert!(
+            service
+                .generate_challenge(extra.clone(), b"key".to_vec())
+                .await
+                .is_none()
+        );
+
+        // A connection that gave up makes room for the next one.
+        service.discard_challenge(&connection(0)).await;
+        assert!(
+            service
+                .generate_challenge(extra, b"key".to_vec())
+                .await
+                .is_some()
+        );
     }
 }
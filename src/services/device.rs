@@ -0,0 +1,111 @@
+use crate::db::{RemovedDevices, Storage};
+use crate::error::{ServerError, ServerResult};
+use crate::logging::Logger;
+use crate::services::ReceiptService;
+use crate::session::{SessionId, SessionManager};
+use hnet_protocol::Packet;
+use std::sync::Arc;
+
+// Logging in with one more device removes the device seen least recently.
+const MAX_DEVICES_PER_USER: i64 = 10;
+
+pub struct DeviceService {
+    session_manager: Arc<SessionManager>,
+    storage: Arc<dyn Storage>,
+    receipt_service: Arc<ReceiptService>,
+    logger: Logger,
+}
+
+impl DeviceService {
+    pub fn new(
+        session_manager: Arc<SessionManager>,
+        storage: Arc<dyn Storage>,
+        receipt_service: Arc<ReceiptService>,
+    ) -> Self {
+        Self {
+            session_manager,
+            storage,
+            receipt_service,
+            logger: Logger::new("DEVICE"),
+        }
+    }
+
+    pub async fn register(&self, public_key: &[u8], device_id: &[u8]) -> ServerResult<()> {
+        let removed = self
+            .storage
+            .register_device(public_key, device_id, MAX_DEVICES_PER_USER)
+            .await?;
+
+        self.dropped(removed).await;
+
+        Ok(())
+    }
+
+    /// Keeps a device that just went offline from expiring while it is still in use.
+    pub async fn touch(&self, device: &SessionId) -> ServerResult<()> {
+        self.storage
+            .touch_device(&device.public_key, &device.device_id)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Drops another device of the sender, one that was lost or sold for example.
+    pub async fn remove(&self, sender: &SessionId, device_id: Vec<u8>) -> ServerResult<()> {
+        // The session asking would be closed before it got the answer.
+        if device_id == sender.device_id {
+            return Err(ServerError::InvalidArgument("device_id"));
+        }
+
+        let removed = self
+            .storage
+            .remove_device(&sender.public_key, &device_id)
+            .await?;
+        let success = !removed.devices.is_empty();
+
+        self.dropped(removed).await;
+
+        self.session_manager
+            .send_to_device(sender, Packet::ProfileUpdated { success })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Removes devices not seen for `older_than_days`, `batch_size` at a time. Returns how many
+    /// were removed.
+    pub async fn purge_stale(&self, older_than_days: i32, batch_size: i64) -> ServerResult<usize> {
+        let mut purged = 0;
+
+        loop {
+            let removed = self
+                .storage
+                .purge_stale_devices(older_than_days, batch_size)
+                .await?;
+
+            if removed.devices.is_empty() {
+                break;
+            }
+
+            purged += removed.devices.len();
+            self.dropped(removed).await;
+        }
+
+        Ok(purged)
+    }
+
+    // A removed device still connected is logged out, and senders learn which messages went with it.
+    async fn dropped(&self, removed: RemovedDevices) {
+        for (public_key, device_id) in removed.devices {
+            self.session_manager
+                .close_session(&SessionId::new(public_key, device_id));
+        }
+
+        if let Err(e) = self.receipt_service.report_expired(removed.lost).await {
+            self.logger.e(&format!(
+                "Failed to report messages lost with a device: {}",
+                e
+            ));
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::db::{GroupRole, Storage};
+use crate::error::{ServerError, ServerResult};
+use crate::session::{SessionId, SessionManager};
+use hnet_protocol::Packet;
+use std::sync::Arc;
+
+const MAX_GROUP_MEMBERS: i64 = 256;
+const MAX_GROUP_NAME_LEN: usize = 128;
+// Groups one user may have created that still exist.
+const MAX_GROUPS_PER_CREATOR: i64 = 100;
+
+pub struct GroupService {
+    session_manager: Arc<SessionManager>,
+    storage: Arc<dyn Storage>,
+}
+
+impl GroupService {
+    pub fn new(session_manager: Arc<SessionManager>, storage: Arc<dyn Storage>) -> Self {
+        Self {
+            session_manager,
+            storage,
+        }
+    }
+
+    pub async fn create_group(
+        &self,
+        creator: &SessionId,
+        name: String,
+        mut members: Vec<Vec<u8>>,
+    ) -> ServerResult<()> {
+        members.retain(|member| *member != creator.public_key);
+        members.sort();
+        members.dedup();
+
+        let valid = !name.is_empty()
+            && name.len() <= MAX_GROUP_NAME_LEN
+            && (members.len() as i64) < MAX_GROUP_MEMBERS;
+
+        // Only real users, so a group cannot be used to queue messages for made-up keys.
+        if valid && self.storage.known_users(&members).await?.len() != members.len() {
+            return Err(ServerError::InvalidArgument("group member"));
+        }
+
+        let packet = if valid {
+            let group_id = self
+                .storage
+                .create_group(
+                    &name,
+                    &creator.public_key,
+                    &members,
+                    MAX_GROUPS_PER_CREATOR,
+                )
+                .await?
+                .ok_or(ServerError::LimitReached("groups"))?;
+
+            Packet::GroupCreated {
+                success: true,
+                group_id,
+            }
+        } else {
+            Packet::GroupCreated {
+                success: false,
+                group_id: 0,
+            }
+        };
+
+        self.session_manager.send_to_device(creator, packet).await?;
+
+        Ok(())
+    }
+
+    pub async fn add_member(
+        &self,
+        requester: &SessionId,
+        group_id: i64,
+        public_key: Vec<u8>,
+    ) -> ServerResult<()> {
+        if self.storage.find_user(&public_key).await?.is_none() {
+            return Err(ServerError::InvalidArgument("group member"));
+        }
+
+        let success = self.is_admin(requester, group_id).await?
+            && self
+                .storage
+                .add_group_member(group_id, &public_key, MAX_GROUP_MEMBERS)
+                .await?;
+
+        self.reply(requester, group_id, success).await
+    }
+
+    pub async fn remove_member(
+        &self,
+        requester: &SessionId,
+        group_id: i64,
+        public_key: Vec<u8>,
+    ) -> ServerResult<()> {
+        let success = self.is_admin(requester, group_id).await?
+            && self
+                .storage
+                .remove_group_member(group_id, &public_key)
+                .await?;
+
+        self.reply(requester, group_id, success).await
+    }
+
+    pub async fn set_admin(
+        &self,
+        requester: &SessionId,
+        group_id: i64,
+        public_key: Vec<u8>,
+        admin: bool,
+    ) -> ServerResult<()> {
+        let role = if admin {
+            GroupRole::Admin
+        } else {
+            GroupRole::Member
+        };
+
+        // Admins step down by leaving or by handing the role over, never by demoting themselves into an adminless group.
+        let success = public_key != requester.public_key
+            && self.is_admin(requester, group_id).await?
+            && self
+                .storage
+                .set_group_role(group_id, &public_key, role)
+                .await?;
+
+        self.reply(requester, group_id, success).await
+    }
+
+    pub async fn leave_group(&self, requester: &SessionId, group_id: i64) -> ServerResult<()> {
+        let success = self
+            .storage
+            .remove_group_member(group_id, &requester.public_key)
+            .await?;
+
+        self.reply(requester, group_id, success).await
+    }
+
+    async fn is_admin(&self, requester: &SessionId, group_id: i64) -> ServerResult<bool> {
+        let role = self
+            .storage
+            .group_role(group_id, &requester.public_key)
+            .await?;
+
+        Ok(role == Some(GroupRole::Admin))
+    }
+
+    async fn reply(&self, requester: &SessionId, group_id: i64, success: bool) -> ServerResult<()> {
+        self.session_manager
+            .send_to_device(requester, Packet::GroupUpdated { group_id, success })
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use crate::config::RetentionConfig;
+use crate::db::{ExpiredMessage, Storage};
+use crate::error::ServerResult;
+use crate::logging::Logger;
+use crate::metrics::Metrics;
+use crate::services::{DeviceService, ReceiptService};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+pub struct MaintenanceService {
+    receipt_service: Arc<ReceiptService>,
+    device_service: Arc<DeviceService>,
+    storage: Arc<dyn Storage>,
+    metrics: Arc<Metrics>,
+    purge_interval: Duration,
+    default_retention_days: i32,
+    max_retention_days: i32,
+    device_expiry_days: i32,
+    batch_size: i64,
+    logger: Logger,
+}
+
+impl MaintenanceService {
+    pub fn new(
+        receipt_service: Arc<ReceiptService>,
+        device_service: Arc<DeviceService>,
+        storage: Arc<dyn Storage>,
+        metrics: Arc<Metrics>,
+        retention: &RetentionConfig,
+    ) -> Self {
+        Self {
+            receipt_service,
+            device_service,
+            storage,
+            metrics,
+            purge_interval: retention.purge_interval(),
+            default_retention_days: retention.default_days as i32,
+            max_retention_days: retention.max_days as i32,
+            device_expiry_days: retention.device_expiry_days as i32,
+            batch_size: retention.batch_size.into(),
+            logger: Logger::new("MAINTENANCE"),
+        }
+    }
+
+    pub async fn run(&self, shutdown_token: CancellationToken) {
+        let mut interval = tokio::time::interval(self.purge_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = self.purge_expired_pending().await {
+                        self.logger.e(&format!("Failed to purge expired messages: {}", e));
+                    }
+                    if let Err(e) = self.purge_stale_devices().await {
+                        self.logger.e(&format!("Failed to purge stale devices: {}", e));
+                    }
+                    // Counted here rather than on every scrape, the count walks the whole table.
+                    match self.storage.count_pending().await {
+                        Ok(count) => self.metrics.set_pending_messages(count),
+                        Err(e) => self.logger.w(&format!("Failed to count pending messages: {}", e)),
+                    }
+                }
+
+                _ = shutdown_token.cancelled() => break,
+            }
+        }
+    }
+
+    async fn purge_expired_pending(&self) -> ServerResult<()> {
+        let mut purged = 0;
+        // A message queued for several devices has one row per device, and the copies may be split
+        // across batches. Each message is reported once.
+        let mut seen = HashSet::new();
+
+        loop {
+            let expired = self
+                .storage
+                .purge_expired_pending(self.default_retention_days, self.batch_size)
+                .await?;
+
+            if expired.is_empty() {
+                break;
+            }
+
+            purged += expired.len();
+
+            // Reported before the next batch is fetched, a failure there must not lose what is already deleted.
+            self.report_expired(expired, &mut seen).await;
+        }
+
+        purged += self
+            .storage
+            .purge_expired_receipts(self.default_retention_days)
+            .await? as usize;
+
+        // A message may wait this long before it is read, receipts for it must still be accepted.
+        self.storage
+            .purge_sent_messages(self.max_retention_days)
+            .await?;
+
+        if purged > 0 {
+            self.logger.i(&format!(
+                "Purged {} expired pending messages and receipts",
+                purged
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn purge_stale_devices(&self) -> ServerResult<()> {
+        let purged = self
+            .device_service
+            .purge_stale(self.device_expiry_days, self.batch_size)
+            .await?;
+
+        if purged > 0 {
+            self.logger.i(&format!("Removed {} stale devices", purged));
+        }
+
+        Ok(())
+    }
+
+    /// A message is only reported lost if none of its copies reached a device of the recipient.
+    async fn report_expired(
+        &self,
+        expired: Vec<ExpiredMessage>,
+        seen: &mut HashSet<(Vec<u8>, Vec<u8>, i64)>,
+    ) {
+        let lost: Vec<ExpiredMessage> = expired
+            .into_iter()
+            .filter(|message| {
+                !message.delivered
+                    && seen.insert((
+                        message.sender_pubkey.clone(),
+                        message.recipient_pubkey.clone(),
+                        message.client_message_id,
+                    ))
+            })
+            .collect();
+
+        if let Err(e) = self.receipt_service.report_expired(lost).await {
+            self.logger
+                .e(&format!("Failed to report expired messages: {}", e));
+        }
+    }
+}
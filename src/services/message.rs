@@ -1,86 +1,334 @@
-use crate::db::PendingMessage;
-use crate::session::SessionManager;
+use crate::config::QuotaConfig;
+use crate::db::{NewPendingMessage, PendingQuota, Queued, Storage};
+use crate::error::ServerResult;
+use crate::logging::Logger;
+use crate::metrics::Metrics;
+use crate::session::{SessionId, SessionManager};
 use hnet_protocol::Packet;
-use sqlx::PgPool;
 use std::sync::Arc;
 
+/// Sent back in `MessageDelivered` once the server has taken a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DeliveryStatus {
+    /// Handed to at least one live session of every recipient.
+    Delivered = 1,
+    /// Stored until the recipient comes online.
+    Queued = 2,
+    /// No user with that key exists, nothing was stored.
+    UnknownRecipient = 3,
+    /// Group messages only: some members got it, others had no room left in their queue.
+    PartiallyRejected = 4,
+}
+
+/// Sent back in `MessageRejected` so the client can tell the sender why.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RejectReason {
+    MessageTooLarge = 1,
+    QueueFull = 2,
+    QueueBytesExceeded = 3,
+    NotGroupMember = 4,
+}
+
+type Outcome = Result<DeliveryStatus, RejectReason>;
+
 pub struct MessageService {
     session_manager: Arc<SessionManager>,
-    db_pool: PgPool,
+    storage: Arc<dyn Storage>,
+    metrics: Arc<Metrics>,
+    logger: Logger,
+    max_message_bytes: usize,
+    quota: PendingQuota,
 }
 
 impl MessageService {
-    pub fn new(session_manager: Arc<SessionManager>, db_pool: PgPool) -> Self {
+    pub fn new(
+        session_manager: Arc<SessionManager>,
+        storage: Arc<dyn Storage>,
+        quota: &QuotaConfig,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         Self {
             session_manager,
-            db_pool,
+            storage,
+            metrics,
+            logger: Logger::new("MESSAGE"),
+            max_message_bytes: quota.max_message_bytes as usize,
+            quota: PendingQuota {
+                max_messages: quota.max_pending_messages.into(),
+                max_bytes: quota.max_pending_bytes as i64,
+            },
         }
     }
 
     pub async fn route_message(
         &self,
-        sender_pubkey: &[u8],
+        sender: &SessionId,
         sender_enc_pubkey: &[u8],
+        client_message_id: u64,
         recipient_pubkey: Vec<u8>,
         encrypted_content: Vec<u8>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let delivered = self
-            .session_manager
-            .send_to_user(
-                &recipient_pubkey,
-                Packet::MessageReceived {
-                    sender_pubkey: sender_pubkey.to_vec(),
-                    sender_enc_pubkey: sender_enc_pubkey.to_vec(),
-                    encrypted_content: encrypted_content.clone(),
-                },
-            )
-            .await
-            .is_ok();
-
-        if !delivered {
-            PendingMessage::save(
-                &self.db_pool,
-                &recipient_pubkey,
-                sender_pubkey,
+    ) -> ServerResult<()> {
+        let outcome = if encrypted_content.len() > self.max_message_bytes {
+            Err(RejectReason::MessageTooLarge)
+        } else if self.storage.find_user(&recipient_pubkey).await?.is_none() {
+            Ok(DeliveryStatus::UnknownRecipient)
+        } else {
+            self.store_and_deliver(
+                sender,
                 sender_enc_pubkey,
-                encrypted_content,
+                &[recipient_pubkey],
+                None,
+                client_message_id,
+                &encrypted_content,
             )
-            .await?;
-        }
+            .await?
+            .remove(0)
+        };
 
-        let result = self
-            .session_manager
-            .send_to_user(sender_pubkey, Packet::MessageDelivered { success: true })
-            .await;
+        self.reply(sender, client_message_id, outcome).await
+    }
 
-        if let Err(e) = result {
-            return Err(e.into());
-        }
+    pub async fn route_group_message(
+        &self,
+        sender: &SessionId,
+        sender_enc_pubkey: &[u8],
+        client_message_id: u64,
+        group_id: i64,
+        encrypted_content: Vec<u8>,
+    ) -> ServerResult<()> {
+        let is_member = self
+            .storage
+            .group_role(group_id, &sender.public_key)
+            .await?
+            .is_some();
+
+        let outcome = if !is_member {
+            Err(RejectReason::NotGroupMember)
+        } else if encrypted_content.len() > self.max_message_bytes {
+            Err(RejectReason::MessageTooLarge)
+        } else {
+            let mut members = self.storage.group_members(group_id).await?;
+            members.retain(|member| *member != sender.public_key);
+            // Members are checked when they join, this keeps keys that slipped in from ever getting a queue.
+            let members = self.storage.known_users(&members).await?;
+
+            // A member with a full queue misses this message, the rest of the group still gets it.
+            let outcomes = self
+                .store_and_deliver(
+                    sender,
+                    sender_enc_pubkey,
+                    &members,
+                    Some(group_id),
+                    client_message_id,
+                    &encrypted_content,
+                )
+                .await?;
+
+            group_outcome(&outcomes)
+        };
+
+        self.reply(sender, client_message_id, outcome).await
+    }
+
+    async fn reply(
+        &self,
+        sender: &SessionId,
+        client_message_id: u64,
+        outcome: Outcome,
+    ) -> ServerResult<()> {
+        let packet = match outcome {
+            Ok(status) => {
+                self.logger
+                    .d(&format!("Message {}: {:?}", client_message_id, status));
+
+                Packet::MessageDelivered {
+                    client_message_id,
+                    status: status as u8,
+                }
+            }
+            Err(reason) => {
+                self.logger.i(&format!(
+                    "Message {} rejected: {:?}",
+                    client_message_id, reason
+                ));
+
+                Packet::MessageRejected {
+                    client_message_id,
+                    code: reason as u8,
+                }
+            }
+        };
+
+        self.session_manager.send_to_device(sender, packet).await?;
 
         Ok(())
     }
 
-    pub async fn deliver_pending_messages(
+    // Every message is stored per device until that device acks it, so a live send is only a fast path.
+    // All recipients are stored in one go, a failure leaves none of them with the message.
+    async fn store_and_deliver(
         &self,
-        user_pubkey: &[u8],
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let pending = PendingMessage::get_and_delete_for_user(&self.db_pool, user_pubkey).await?;
-
-        if !pending.is_empty() {
-            for msg in pending {
-                self.session_manager
-                    .send_to_user(
-                        user_pubkey,
-                        Packet::MessageReceived {
-                            sender_pubkey: msg.sender_pubkey,
-                            sender_enc_pubkey: msg.sender_enc_pubkey,
-                            encrypted_content: msg.encrypted_content,
-                        },
+        sender: &SessionId,
+        sender_enc_pubkey: &[u8],
+        recipients: &[Vec<u8>],
+        group_id: Option<i64>,
+        client_message_id: u64,
+        encrypted_content: &[u8],
+    ) -> ServerResult<Vec<Outcome>> {
+        let queued = self
+            .storage
+            .queue_pending(
+                recipients,
+                NewPendingMessage {
+                    group_id,
+                    client_message_id: client_message_id as i64,
+                    sender_pubkey: &sender.public_key,
+                    sender_enc_pubkey,
+                    encrypted_content,
+                },
+                self.quota,
+            )
+            .await?;
+
+        let mut outcomes = Vec::with_capacity(recipients.len());
+
+        for (recipient_pubkey, queued) in recipients.iter().zip(queued) {
+            let devices = match queued {
+                Queued::Devices(devices) => devices,
+                // A resend of a message already stored, the copies queued the first time still go out.
+                Queued::Duplicate => {
+                    outcomes.push(Ok(DeliveryStatus::Queued));
+                    continue;
+                }
+                Queued::QueueFull => {
+                    outcomes.push(Err(RejectReason::QueueFull));
+                    continue;
+                }
+                Queued::QueueBytesExceeded => {
+                    outcomes.push(Err(RejectReason::QueueBytesExceeded));
+                    continue;
+                }
+            };
+
+            // Nobody has logged in with this key yet, every device registered by the next login gets a copy.
+            if devices.is_empty() {
+                self.metrics.delivery(false);
+            }
+
+            let mut delivered = false;
+
+            for (device_id, message_id) in devices {
+                let sent = self
+                    .session_manager
+                    .send_to_device(
+                        &SessionId::new(recipient_pubkey.clone(), device_id),
+                        received_packet(
+                            message_id,
+                            group_id,
+                            client_message_id,
+                            sender.public_key.clone(),
+                            sender_enc_pubkey.to_vec(),
+                            encrypted_content.to_vec(),
+                        ),
                     )
-                    .await?;
+                    .await;
+
+                self.metrics.delivery(sent.is_ok());
+                delivered |= sent.is_ok();
             }
+
+            outcomes.push(Ok(if delivered {
+                DeliveryStatus::Delivered
+            } else {
+                DeliveryStatus::Queued
+            }));
         }
 
+        Ok(outcomes)
+    }
+
+    pub async fn acknowledge_message(
+        &self,
+        recipient: &SessionId,
+        message_id: i64,
+    ) -> ServerResult<()> {
+        self.storage
+            .acknowledge_pending(&recipient.public_key, &recipient.device_id, message_id)
+            .await?;
+
         Ok(())
     }
+
+    pub async fn deliver_pending_messages(&self, recipient: &SessionId) -> ServerResult<()> {
+        let pending = self
+            .storage
+            .pending_for_device(&recipient.public_key, &recipient.device_id)
+            .await?;
+
+        // Waits out a full queue, the backlog may be far longer than the queue. Whatever is left
+        // when the session goes away stays stored for the next login.
+        for msg in pending {
+            self.session_manager
+                .send_to_device_waiting(
+                    recipient,
+                    received_packet(
+                        msg.id,
+                        msg.group_id,
+                        msg.client_message_id as u64,
+                        msg.sender_pubkey,
+                        msg.sender_enc_pubkey,
+                        msg.encrypted_content,
+                    ),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Rejected only when every member rejected it, with the first member's reason.
+fn group_outcome(outcomes: &[Outcome]) -> Outcome {
+    let rejected = outcomes.iter().filter(|outcome| outcome.is_err()).count();
+
+    match outcomes.first() {
+        Some(Err(reason)) if rejected == outcomes.len() => Err(*reason),
+        _ if rejected > 0 => Ok(DeliveryStatus::PartiallyRejected),
+        _ if outcomes
+            .iter()
+            .all(|outcome| *outcome == Ok(DeliveryStatus::Delivered)) =>
+        {
+            Ok(DeliveryStatus::Delivered)
+        }
+        _ => Ok(DeliveryStatus::Queued),
+    }
+}
+
+fn received_packet(
+    message_id: i64,
+    group_id: Option<i64>,
+    client_message_id: u64,
+    sender_pubkey: Vec<u8>,
+    sender_enc_pubkey: Vec<u8>,
+    encrypted_content: Vec<u8>,
+) -> Packet {
+    match group_id {
+        Some(group_id) => Packet::GroupMessageReceived {
+            message_id,
+            client_message_id,
+            group_id,
+            sender_pubkey,
+            sender_enc_pubkey,
+            encrypted_content,
+        },
+        None => Packet::MessageReceived {
+            message_id,
+            client_message_id,
+            sender_pubkey,
+            sender_enc_pubkey,
+            encrypted_content,
+        },
+    }
 }
@@ -1,7 +1,19 @@
 mod auth;
+mod device;
+mod group;
+mod maintenance;
 mod message;
+mod presence;
+mod receipt;
+mod typing;
 mod user;
 
-pub use auth::AuthService;
+pub use auth::{AuthService, LoggedIn};
+pub use device::DeviceService;
+pub use group::GroupService;
+pub use maintenance::MaintenanceService;
 pub use message::MessageService;
+pub use presence::PresenceService;
+pub use receipt::ReceiptService;
+pub use typing::TypingService;
 pub use user::UserService;
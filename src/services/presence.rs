@@ -0,0 +1,266 @@
+use crate::db::Storage;
+use crate::error::ServerResult;
+use crate::session::{SessionId, SessionManager};
+use chrono::{DateTime, Utc};
+use hnet_protocol::Packet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const MAX_PRESENCE_SUBSCRIPTIONS: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LastSeenVisibility {
+    Everyone = 0,
+    GroupMembers = 1,
+    Nobody = 2,
+}
+
+impl LastSeenVisibility {
+    fn from_u8(visibility: u8) -> Option<Self> {
+        match visibility {
+            0 => Some(LastSeenVisibility::Everyone),
+            1 => Some(LastSeenVisibility::GroupMembers),
+            2 => Some(LastSeenVisibility::Nobody),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    // watched public key -> sessions that want its presence
+    watchers: HashMap<Vec<u8>, HashSet<SessionId>>,
+    // session -> watched public keys, so a disconnect can clean up after itself
+    watching: HashMap<SessionId, HashSet<Vec<u8>>>,
+}
+
+pub struct PresenceService {
+    session_manager: Arc<SessionManager>,
+    storage: Arc<dyn Storage>,
+    subscriptions: Mutex<Subscriptions>,
+}
+
+impl PresenceService {
+    pub fn new(session_manager: Arc<SessionManager>, storage: Arc<dyn Storage>) -> Self {
+        Self {
+            session_manager,
+            storage,
+            subscriptions: Mutex::new(Subscriptions::default()),
+        }
+    }
+
+    pub async fn subscribe(
+        &self,
+        subscriber: &SessionId,
+        public_keys: Vec<Vec<u8>>,
+    ) -> ServerResult<()> {
+        let mut added = Vec::new();
+
+        {
+            let mut subscriptions = self.subscriptions.lock().await;
+            let Subscriptions { watchers, watching } = &mut *subscriptions;
+            let watched = watching.entry(subscriber.clone()).or_default();
+
+            for public_key in public_keys {
+                if watched.len() >= MAX_PRESENCE_SUBSCRIPTIONS {
+                    break;
+                }
+
+                if public_key != subscriber.public_key && watched.insert(public_key.clone()) {
+                    watchers
+                        .entry(public_key.clone())
+                        .or_default()
+                        .insert(subscriber.clone());
+                    added.push(public_key);
+                }
+            }
+        }
+
+        // New subscribers start from the current state instead of waiting for the next change.
+        for public_key in added {
+            let Some(profile) = self.storage.find_user(&public_key).await? else {
+                continue;
+            };
+
+            if !self
+                .is_visible(
+                    &subscriber.public_key,
+                    &public_key,
+                    profile.last_seen_visibility,
+                )
+                .await?
+            {
+                continue;
+            }
+
+            let _ = self
+                .session_manager
+                .send_to_device(
+                    subscriber,
+                    Packet::PresenceChanged {
+                        online: self.session_manager.online_devices(&public_key) > 0,
+                        last_seen_at: profile.last_seen_at.map(|at| at.timestamp()),
+                        public_key,
+                    },
+                )
+                .await;
+        }
+
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, subscriber: &SessionId, public_keys: Vec<Vec<u8>>) {
+        let mut subscriptions = self.subscriptions.lock().await;
+
+        for public_key in public_keys {
+            if let Some(watched) = subscriptions.watching.get_mut(subscriber) {
+                watched.remove(&public_key);
+            }
+
+            remove_watcher(&mut subscriptions.watchers, &public_key, subscriber);
+        }
+    }
+
+    pub async fn set_last_seen_visibility(
+        &self,
+        sender: &SessionId,
+        visibility: u8,
+    ) -> ServerResult<()> {
+        let success = match LastSeenVisibility::from_u8(visibility) {
+            Some(visibility) => {
+                self.storage
+                    .set_last_seen_visibility(&sender.public_key, visibility as i16)
+                    .await?
+            }
+            None => false,
+        };
+
+        self.session_manager
+            .send_to_device(sender, Packet::ProfileUpdated { success })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Called once a device has logged in, announces the user if it is their first device.
+    /// `came_online` comes from the session manager, which decides it atomically with the login.
+    pub async fn user_connected(&self, id: &SessionId, came_online: bool) -> ServerResult<()> {
+        let now = Utc::now();
+
+        self.storage.set_last_seen(&id.public_key, now).await?;
+
+        if came_online {
+            self.broadcast(&id.public_key, true, now).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Called after a device session was removed, announces the user once their last device left.
+    pub async fn user_disconnected(&self, id: &SessionId, went_offline: bool) -> ServerResult<()> {
+        {
+            let mut subscriptions = self.subscriptions.lock().await;
+
+            if let Some(watched) = subscriptions.watching.remove(id) {
+                for public_key in watched {
+                    remove_watcher(&mut subscriptions.watchers, &public_key, id);
+                }
+            }
+        }
+
+        if went_offline {
+            let now = Utc::now();
+
+            self.storage.set_last_seen(&id.public_key, now).await?;
+            self.broadcast(&id.public_key, false, now).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn broadcast(
+        &self,
+        public_key: &[u8],
+        online: bool,
+        last_seen_at: DateTime<Utc>,
+    ) -> ServerResult<()> {
+        let watchers: Vec<SessionId> =
+            match self.subscriptions.lock().await.watchers.get(public_key) {
+                Some(watchers) => watchers.iter().cloned().collect(),
+                None => return Ok(()),
+            };
+
+        let Some(profile) = self.storage.find_user(public_key).await? else {
+            return Ok(());
+        };
+
+        // One user often watches from several devices, only ask storage once per user.
+        let mut visible: HashMap<Vec<u8>, bool> = HashMap::new();
+
+        for watcher in watchers {
+            let is_visible = match visible.get(&watcher.public_key) {
+                Some(&is_visible) => is_visible,
+                None => {
+                    let is_visible = self
+                        .is_visible(
+                            &watcher.public_key,
+                            public_key,
+                            profile.last_seen_visibility,
+                        )
+                        .await?;
+                    visible.insert(watcher.public_key.clone(), is_visible);
+                    is_visible
+                }
+            };
+
+            if !is_visible {
+                continue;
+            }
+
+            let _ = self
+                .session_manager
+                .send_to_device(
+                    &watcher,
+                    Packet::PresenceChanged {
+                        public_key: public_key.to_vec(),
+                        online,
+                        last_seen_at: Some(last_seen_at.timestamp()),
+                    },
+                )
+                .await;
+        }
+
+        Ok(())
+    }
+
+    async fn is_visible(
+        &self,
+        viewer: &[u8],
+        target: &[u8],
+        visibility: i16,
+    ) -> ServerResult<bool> {
+        match LastSeenVisibility::from_u8(visibility as u8) {
+            Some(LastSeenVisibility::Everyone) => Ok(true),
+            Some(LastSeenVisibility::GroupMembers) => {
+                Ok(self.storage.share_group(viewer, target).await?)
+            }
+            Some(LastSeenVisibility::Nobody) | None => Ok(false),
+        }
+    }
+}
+
+fn remove_watcher(
+    watchers: &mut HashMap<Vec<u8>, HashSet<SessionId>>,
+    public_key: &[u8],
+    subscriber: &SessionId,
+) {
+    if let Some(sessions) = watchers.get_mut(public_key) {
+        sessions.remove(subscriber);
+
+        if sessions.is_empty() {
+            watchers.remove(public_key);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use crate::db::{ExpiredMessage, NewReceipt, Storage};
+use crate::error::{ServerError, ServerResult};
+use crate::session::{SessionId, SessionManager};
+use hnet_protocol::Packet;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const MAX_RECEIPT_IDS: usize = 256;
+// Across all devices of the recipient, receipts past this are dropped until some are acked.
+const MAX_PENDING_RECEIPTS: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ReceiptKind {
+    Delivered = 1,
+    Read = 2,
+    /// Sent by the server when messages expired before reaching the recipient, who appears as the reader.
+    Expired = 3,
+}
+
+impl ReceiptKind {
+    // Only the kinds a client may send.
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(ReceiptKind::Delivered),
+            2 => Some(ReceiptKind::Read),
+            _ => None,
+        }
+    }
+}
+
+pub struct ReceiptService {
+    session_manager: Arc<SessionManager>,
+    storage: Arc<dyn Storage>,
+}
+
+impl ReceiptService {
+    pub fn new(session_manager: Arc<SessionManager>, storage: Arc<dyn Storage>) -> Self {
+        Self {
+            session_manager,
+            storage,
+        }
+    }
+
+    /// Routes a receipt back to every device of the original sender, kept until each device acks it.
+    pub async fn send_receipt(
+        &self,
+        reader: &SessionId,
+        sender_pubkey: Vec<u8>,
+        client_message_ids: Vec<u64>,
+        kind: u8,
+    ) -> ServerResult<()> {
+        let Some(kind) = ReceiptKind::from_u8(kind) else {
+            return Err(ServerError::InvalidArgument("receipt kind"));
+        };
+
+        if client_message_ids.is_empty() || client_message_ids.len() > MAX_RECEIPT_IDS {
+            return Err(ServerError::InvalidArgument("receipt message ids"));
+        }
+        if sender_pubkey == reader.public_key {
+            return Err(ServerError::InvalidArgument("receipt sender"));
+        }
+
+        if kind == ReceiptKind::Read {
+            let read_receipts = self
+                .storage
+                .find_user(&reader.public_key)
+                .await?
+                .is_none_or(|profile| profile.read_receipts);
+
+            if !read_receipts {
+                return Ok(());
+            }
+        }
+
+        let requested_ids: Vec<i64> = client_message_ids.iter().map(|&id| id as i64).collect();
+
+        // Only messages the sender really sent to the reader, so nobody can forge receipts.
+        let stored_ids = self
+            .storage
+            .sent_message_ids(&sender_pubkey, &reader.public_key, &requested_ids)
+            .await?;
+
+        if stored_ids.is_empty() {
+            return Ok(());
+        }
+
+        self.route(&sender_pubkey, &reader.public_key, &stored_ids, kind)
+            .await
+    }
+
+    /// Tells senders which of their messages expired undelivered. Stored like any receipt, so a
+    /// sender who is offline now learns it on their next login.
+    pub async fn report_expired(&self, expired: Vec<ExpiredMessage>) -> ServerResult<()> {
+        let mut expired_ids: HashMap<(Vec<u8>, Vec<u8>), Vec<i64>> = HashMap::new();
+
+        for message in expired {
+            expired_ids
+                .entry((message.sender_pubkey, message.recipient_pubkey))
+                .or_default()
+                .push(message.client_message_id);
+        }
+
+        // One sender failing does not cost the others their report, the first error is returned.
+        let mut result = Ok(());
+
+        for ((sender_pubkey, recipient_pubkey), client_message_ids) in expired_ids {
+            for chunk in client_message_ids.chunks(MAX_RECEIPT_IDS) {
+                let routed = self
+                    .route(
+                        &sender_pubkey,
+                        &recipient_pubkey,
+                        chunk,
+                        ReceiptKind::Expired,
+                    )
+                    .await;
+
+                result = result.and(routed);
+            }
+        }
+
+        result
+    }
+
+    // Stored before sending, like messages, and only dropped once the device acks it.
+    async fn route(
+        &self,
+        sender_pubkey: &[u8],
+        reader_pubkey: &[u8],
+        client_message_ids: &[i64],
+        kind: ReceiptKind,
+    ) -> ServerResult<()> {
+        for device_id in self.storage.list_devices(sender_pubkey).await? {
+            let device = SessionId::new(sender_pubkey.to_vec(), device_id);
+
+            let Some(receipt_id) = self
+                .storage
+                .save_receipt(
+                    NewReceipt {
+                        recipient_pubkey: &device.public_key,
+                        recipient_device_id: &device.device_id,
+                        reader_pubkey,
+                        client_message_ids,
+                        kind: kind as i16,
+                    },
+                    MAX_PENDING_RECEIPTS,
+                )
+                .await?
+            else {
+                continue;
+            };
+
+            // An offline device gets it from storage on its next login.
+            let _ = self
+                .session_manager
+                .send_to_device(
+                    &device,
+                    Packet::ReceiptReceived {
+                        receipt_id,
+                        reader_pubkey: reader_pubkey.to_vec(),
+                        client_message_ids: client_message_ids
+                            .iter()
+                            .map(|&id| id as u64)
+                            .collect(),
+                        kind: kind as u8,
+                    },
+                )
+                .await;
+        }
+
+        Ok(())
+    }
+
+    pub async fn acknowledge_receipt(
+        &self,
+        recipient: &SessionId,
+        receipt_id: i64,
+    ) -> ServerResult<()> {
+        self.storage
+            .acknowledge_receipt(&recipient.public_key, &recipient.device_id, receipt_id)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn deliver_pending_receipts(&self, recipient: &SessionId) -> ServerResult<()> {
+        let pending = self
+            .storage
+            .pending_receipts_for_device(&recipient.public_key, &recipient.device_id)
+            .await?;
+
+        // Receipts stay stored until acked, whatever the session does not take now comes again next login.
+        for receipt in pending {
+            self.session_manager
+                .send_to_device_waiting(
+                    recipient,
+                    Packet::ReceiptReceived {
+                        receipt_id: receipt.id,
+                        reader_pubkey: receipt.reader_pubkey,
+                        client_message_ids: receipt
+                            .client_message_ids
+                            .iter()
+                            .map(|&id| id as u64)
+                            .collect(),
+                        kind: receipt.kind as u8,
+                    },
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryStorage;
+
+    fn service() -> ReceiptService {
+        ReceiptService::new(
+            Arc::new(SessionManager::new(16)),
+            Arc::new(MemoryStorage::new()),
+        )
+    }
+
+    fn reader() -> SessionId {
+        SessionId::new(b"reader".to_vec(), b"phone".to_vec())
+    }
+
+    async fn rejection(sender_pubkey: &[u8], client_message_ids: Vec<u64>, kind: u8) -> String {
+        match service()
+            .send_receipt(&reader(), sender_pubkey.to_vec(), client_message_ids, kind)
+            .await
+        {
+            Err(e @ ServerError::InvalidArgument(_)) => e.to_string(),
+            other => panic!("Expected InvalidArgument, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_kinds() {
+        assert_eq!(
+            rejection(b"sender", vec![1], 0).await,
+            "Invalid receipt kind"
+        );
+        // Only the server reports expired messages.
+        assert_eq!(
+            rejection(b"sender", vec![1], ReceiptKind::Expired as u8).await,
+            "Invalid receipt kind"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_id_lists() {
+        assert_eq!(
+            rejection(b"sender", Vec::new(), 1).await,
+            "Invalid receipt message ids"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_too_many_ids() {
+        let ids = (0..=MAX_RECEIPT_IDS as u64).collect();
+
+        assert_eq!(
+            rejection(b"sender", ids, 1).await,
+            "Invalid receipt message ids"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_receipts_to_yourself() {
+        assert_eq!(
+            rejection(b"reader", vec![1], 2).await,
+            "Invalid receipt sender"
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_ids_the_sender_never_sent() {
+        let service = service();
+
+        assert!(
+            service
+                .send_receipt(&reader(), b"sender".to_vec(), vec![1], 1)
+                .await
+                .is_ok()
+        );
+        assert!(
+            service
+                .storage
+                .pending_receipts_for_device(b"sender", b"phone")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use crate::session::{SessionId, SessionManager};
+use hnet_protocol::Packet;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+const TYPING_WINDOW: Duration = Duration::from_secs(5);
+const MAX_TYPING_EVENTS_PER_WINDOW: u32 = 10;
+const MAX_TRACKED_SENDERS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TypingKind {
+    Stopped = 0,
+    Typing = 1,
+    Recording = 2,
+}
+
+impl TypingKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(TypingKind::Stopped),
+            1 => Some(TypingKind::Typing),
+            2 => Some(TypingKind::Recording),
+            _ => None,
+        }
+    }
+}
+
+struct TypingWindow {
+    started_at: Instant,
+    events: u32,
+}
+
+impl TypingWindow {
+    fn is_expired(&self) -> bool {
+        self.started_at.elapsed() >= TYPING_WINDOW
+    }
+}
+
+/// Relays typing events to whoever is online right now, nothing is ever stored.
+pub struct TypingService {
+    session_manager: Arc<SessionManager>,
+    windows: Mutex<HashMap<Vec<u8>, TypingWindow>>,
+}
+
+impl TypingService {
+    pub fn new(session_manager: Arc<SessionManager>) -> Self {
+        Self {
+            session_manager,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn relay(&self, sender: &SessionId, recipient_pubkey: Vec<u8>, kind: u8) {
+        let Some(kind) = TypingKind::from_u8(kind) else {
+            return;
+        };
+
+        if recipient_pubkey == sender.public_key || !self.allow(&sender.public_key).await {
+            return;
+        }
+
+        // An offline recipient simply misses the event.
+        let _ = self
+            .session_manager
+            .send_to_user(
+                &recipient_pubkey,
+                Packet::TypingIndicator {
+                    sender_pubkey: sender.public_key.clone(),
+                    kind: kind as u8,
+                },
+            )
+            .await;
+    }
+
+    async fn allow(&self, sender_pubkey: &[u8]) -> bool {
+        let mut windows = self.windows.lock().await;
+
+        if windows.len() >= MAX_TRACKED_SENDERS {
+            windows.retain(|_, window| !window.is_expired());
+        }
+
+        let window = windows
+            .entry(sender_pubkey.to_vec())
+            .or_insert_with(|| TypingWindow {
+                started_at: Instant::now(),
+                events: 0,
+            });
+
+        if window.is_expired() {
+            window.started_at = Instant::now();
+            window.events = 0;
+        }
+
+        if window.events >= MAX_TYPING_EVENTS_PER_WINDOW {
+            return false;
+        }
+
+        window.events += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnet::transport::PacketSink;
+    use crate::session::{ConnectionId, Session};
+    use hnet_protocol::RawPacket;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio_util::sync::CancellationToken;
+    use tokio_util::task::TaskTracker;
+
+    struct CountingSink(Arc<AtomicUsize>);
+
+    impl PacketSink for CountingSink {
+        async fn write_packet(&mut self, _raw: &RawPacket) -> std::io::Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    struct Recipient {
+        id: SessionId,
+        connection: ConnectionId,
+        received: Arc<AtomicUsize>,
+        tracker: TaskTracker,
+    }
+
+    impl Recipient {
+        fn log_in(session_manager: &SessionManager) -> Self {
+            let temp_id = SessionId::new(b"127.0.0.1:1".to_vec(), Vec::new());
+            let id = SessionId::new(b"recipient".to_vec(), b"phone".to_vec());
+            let connection = ConnectionId::next();
+            let received = Arc::new(AtomicUsize::new(0));
+            let tracker = TaskTracker::new();
+
+            session_manager.add_session(Session::new(
+                temp_id.clone(),
+                connection,
+                CountingSink(Arc::clone(&received)),
+                CancellationToken::new(),
+                &tracker,
+            ));
+            session_manager.log_in(&temp_id, id.clone());
+
+            Self {
+                id,
+                connection,
+                received,
+                tracker,
+            }
+        }
+
+        // Closing the session lets its writer drain the queue, so the count is final.
+        async fn received(self, session_manager: &SessionManager) -> usize {
+            session_manager.remove_session(&self.id, self.connection);
+            self.tracker.close();
+            self.tracker.wait().await;
+
+            self.received.load(Ordering::Relaxed)
+        }
+    }
+
+    fn sender() -> SessionId {
+        SessionId::new(b"sender".to_vec(), b"laptop".to_vec())
+    }
+
+    #[tokio::test]
+    async fn ten_indicators_per_window_reach_the_recipient() {
+        let session_manager = Arc::new(SessionManager::new(16));
+        let service = TypingService::new(Arc::clone(&session_manager));
+        let recipient = Recipient::log_in(&session_manager);
+
+        for _ in 0..=MAX_TYPING_EVENTS_PER_WINDOW {
+            service
+                .relay(&sender(), b"recipient".to_vec(), TypingKind::Typing as u8)
+                .await;
+        }
+
+        service
+            .windows
+            .lock()
+            .await
+            .get_mut(b"sender".as_slice())
+            .unwrap()
+            .started_at -= TYPING_WINDOW;
+
+        // A new window starts over.
+        service
+            .relay(&sender(), b"recipient".to_vec(), TypingKind::Stopped as u8)
+            .await;
+
+        assert_eq!(
+            recipient.received(&session_manager).await,
+            MAX_TYPING_EVENTS_PER_WINDOW as usize + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn indicators_to_offline_recipients_are_dropped() {
+        let session_manager = Arc::new(SessionManager::new(16));
+        let service = TypingService::new(Arc::clone(&session_manager));
+
+        service
+            .relay(&sender(), b"recipient".to_vec(), TypingKind::Typing as u8)
+            .await;
+
+        // Nothing is kept for later, logging in afterwards brings no indicator.
+        let recipient = Recipient::log_in(&session_manager);
+
+        assert_eq!(recipient.received(&session_manager).await, 0);
+    }
+}
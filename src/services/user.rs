@@ -1,106 +1,186 @@
-use crate::db::models::UserProfile;
-use crate::session::SessionManager;
-use hnet_protocol::Packet;
-use sqlx::PgPool;
+use crate::db::Storage;
+use crate::error::{ServerError, ServerResult};
+use crate::session::{SessionId, SessionManager};
+use hnet_protocol::{Packet, UserSearchResult};
 use std::sync::Arc;
 
+const MIN_QUERY_LEN: usize = 3;
+const MAX_QUERY_LEN: usize = 64;
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 50;
+const MAX_SEARCH_RESULTS: u32 = 200;
+
 pub struct UserService {
     session_manager: Arc<SessionManager>,
-    db_pool: PgPool,
+    storage: Arc<dyn Storage>,
+    max_retention_days: u32,
 }
 
 impl UserService {
-    pub fn new(session_manager: Arc<SessionManager>, db_pool: PgPool) -> Self {
+    pub fn new(
+        session_manager: Arc<SessionManager>,
+        storage: Arc<dyn Storage>,
+        max_retention_days: u32,
+    ) -> Self {
         Self {
             session_manager,
-            db_pool,
+            storage,
+            max_retention_days,
         }
     }
 
     pub async fn set_profile(
         &self,
-        public_key: &[u8],
+        sender: &SessionId,
         encryption_pubkey: Vec<u8>,
         first_name: String,
         username: Option<String>,
         last_name: Option<String>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let existing = UserProfile::find_by_pubkey(&self.db_pool, public_key).await?;
+    ) -> ServerResult<()> {
+        let public_key = sender.public_key.as_slice();
+        let existing = self.storage.find_user(public_key).await?;
 
         if existing.is_some() {
-            UserProfile::update_profile(
-                &self.db_pool,
-                public_key,
-                &encryption_pubkey,
-                &first_name,
-                username.as_deref(),
-                last_name.as_deref(),
-            )
+            self.storage
+                .update_user(
+                    public_key,
+                    &encryption_pubkey,
+                    &first_name,
+                    username.as_deref(),
+                    last_name.as_deref(),
+                )
+                .await?;
+        } else {
+            self.storage
+                .create_user(
+                    public_key,
+                    &encryption_pubkey,
+                    &first_name,
+                    username.as_deref(),
+                    last_name.as_deref(),
+                )
+                .await?;
+        }
+
+        self.session_manager
+            .send_to_device(sender, Packet::ProfileUpdated { success: true })
             .await?;
+
+        Ok(())
+    }
+
+    /// Sets how long undelivered messages to this user are kept, 0 restores the server default.
+    pub async fn set_pending_retention(&self, sender: &SessionId, days: u32) -> ServerResult<()> {
+        let success = if days > self.max_retention_days {
+            false
         } else {
-            UserProfile::create(
-                &self.db_pool,
-                public_key,
-                &encryption_pubkey,
-                &first_name,
-                username.as_deref(),
-                last_name.as_deref(),
-            )
+            let days = (days > 0).then_some(days as i32);
+
+            self.storage
+                .set_pending_retention(&sender.public_key, days)
+                .await?
+        };
+
+        self.session_manager
+            .send_to_device(sender, Packet::ProfileUpdated { success })
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_read_receipts(&self, sender: &SessionId, enabled: bool) -> ServerResult<()> {
+        let success = self
+            .storage
+            .set_read_receipts(&sender.public_key, enabled)
             .await?;
-        }
 
         self.session_manager
-            .send_to_user(public_key, Packet::ProfileUpdated { success: true })
+            .send_to_device(sender, Packet::ProfileUpdated { success })
             .await?;
 
         Ok(())
     }
 
+    pub async fn set_name_discovery(&self, sender: &SessionId, enabled: bool) -> ServerResult<()> {
+        let success = self
+            .storage
+            .set_discoverable_by_name(&sender.public_key, enabled)
+            .await?;
+
+        self.session_manager
+            .send_to_device(sender, Packet::ProfileUpdated { success })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Matches usernames, and names of users who allow it, by prefix or similarity.
+    /// Paging stops after `MAX_SEARCH_RESULTS` so a query cannot walk the whole table.
     pub async fn search_user(
         &self,
-        requester_pubkey: &[u8],
+        requester: &SessionId,
         query: String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let user = sqlx::query_as::<_, UserProfile>("SELECT * FROM users WHERE username = $1")
-            .bind(&query)
-            .fetch_optional(&self.db_pool)
-            .await?;
+        offset: u32,
+        limit: u32,
+    ) -> ServerResult<()> {
+        let query = query.trim().to_lowercase();
+        let query_len = query.chars().count();
 
-        if let Some(user) = user {
-            self.session_manager
-                .send_to_user(
-                    requester_pubkey,
-                    Packet::UserFound {
-                        public_key: user.public_key,
-                        encryption_pubkey: user.encryption_pubkey,
-                        username: user.username,
-                        first_name: user.first_name,
-                        last_name: user.last_name,
-                    },
-                )
-                .await?;
-        } else {
-            self.session_manager
-                .send_to_user(requester_pubkey, Packet::UserNotFound)
+        let mut results = Vec::new();
+        let mut has_more = false;
+
+        if (MIN_QUERY_LEN..=MAX_QUERY_LEN).contains(&query_len) && offset < MAX_SEARCH_RESULTS {
+            let limit = match limit {
+                0 => DEFAULT_PAGE_SIZE,
+                limit => limit.min(MAX_PAGE_SIZE),
+            }
+            .min(MAX_SEARCH_RESULTS - offset);
+
+            // One extra row tells whether another page exists.
+            let mut users = self
+                .storage
+                .search_users(&query, limit as i64 + 1, offset as i64)
                 .await?;
+
+            if users.len() > limit as usize {
+                users.truncate(limit as usize);
+                has_more = offset + limit < MAX_SEARCH_RESULTS;
+            }
+
+            results = users
+                .into_iter()
+                .map(|user| UserSearchResult {
+                    public_key: user.public_key,
+                    encryption_pubkey: user.encryption_pubkey,
+                    username: user.username,
+                    first_name: user.first_name,
+                    last_name: user.last_name,
+                })
+                .collect();
         }
 
+        self.session_manager
+            .send_to_device(requester, Packet::SearchResults { results, has_more })
+            .await?;
+
         Ok(())
     }
 
-    pub async fn get_encryption_pubkey(
-        &self,
-        auth_pubkey: &[u8],
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
-
-        if let Some(enc_pubkey) = self.session_manager.get_session_enc_pubkey(auth_pubkey.to_vec()).await {
+    pub async fn get_encryption_pubkey(&self, auth_pubkey: &[u8]) -> ServerResult<Vec<u8>> {
+        if let Some(enc_pubkey) = self
+            .session_manager
+            .get_session_enc_pubkey(auth_pubkey.to_vec())
+            .await
+        {
             Ok(enc_pubkey)
         } else {
-            if let Some(profile) = UserProfile::find_by_pubkey(&self.db_pool, auth_pubkey).await? {
-                self.session_manager.put_session_enc_pubkey(auth_pubkey.to_vec(), profile.encryption_pubkey.clone()).await;
+            if let Some(profile) = self.storage.find_user(auth_pubkey).await? {
+                self.session_manager
+                    .put_session_enc_pubkey(auth_pubkey.to_vec(), profile.encryption_pubkey.clone())
+                    .await;
                 Ok(profile.encryption_pubkey)
             } else {
-                Err("User not found".into())
+                Err(ServerError::ProfileRequired)
             }
         }
     }
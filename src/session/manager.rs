@@ -1,31 +1,84 @@
-use crate::session::Session;
-use hnet_protocol::Packet;
+use crate::session::{ConnectionId, Session, SessionId};
+use hnet_protocol::{Packet, RawPacket};
 use std::collections::HashMap;
 use std::num::NonZeroUsize;
 use std::sync::Arc;
 use lrumap::LruHashMap;
 use tokio::sync::Mutex;
 use dashmap::DashMap;
+use std::time::Duration;
+use tokio::sync::mpsc::error::SendTimeoutError;
+use tokio_util::sync::CancellationToken;
+
+// A writer that takes no packet for this long is stuck on a client that stopped reading.
+const QUEUE_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// device id -> session
+type DeviceSessions = HashMap<Vec<u8>, Session>;
+
+/// What removing a session meant for its user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Removal {
+    /// The session is gone already, or a newer login of the same device owns it.
+    Kept,
+    Removed,
+    /// It was the last logged in device of the user.
+    WentOffline,
+}
 
 pub struct SessionManager {
-    sessions: Arc<DashMap<Vec<u8>, Session>>,
+    // public key -> sessions of its devices
+    sessions: Arc<DashMap<Vec<u8>, DeviceSessions>>,
     session_enc_pubkeys: Arc<Mutex<LruHashMap<Vec<u8>, Vec<u8>>>>
 }
 
 impl SessionManager {
-    pub fn new() -> Self {
+    pub fn new(enc_pubkey_cache_size: usize) -> Self {
         Self {
             sessions: Arc::new(DashMap::new()),
-            session_enc_pubkeys: Arc::new(Mutex::new(LruHashMap::new(10_000)))
+            session_enc_pubkeys: Arc::new(Mutex::new(LruHashMap::new(enc_pubkey_cache_size)))
         }
     }
 
-    pub fn add_session(&self, public_key: Vec<u8>, session: Session) {
-        self.sessions.insert(public_key, session);
+    /// A device logging in again replaces its old session, whose connection is told to close.
+    pub fn add_session(&self, session: Session) {
+        let replaced = self
+            .sessions
+            .entry(session.public_key.clone())
+            .or_default()
+            .insert(session.device_id.clone(), session);
+
+        if let Some(replaced) = replaced {
+            replaced.close();
+        }
     }
 
-    pub fn remove_session(&self, public_key: &[u8]) {
-        self.sessions.remove(public_key);
+    /// Removes the session only while it still belongs to `connection`, so a connection that was
+    /// replaced by a newer login of the same device cannot take the new session down with it.
+    /// Whether the user went offline is decided under the same lock as the removal.
+    pub fn remove_session(&self, id: &SessionId, connection: ConnectionId) -> Removal {
+        let mut removal = Removal::Kept;
+
+        self.sessions
+            .remove_if_mut(&id.public_key, |_, devices| {
+                if devices
+                    .get(&id.device_id)
+                    .is_some_and(|session| session.connection == connection)
+                    && let Some(session) = devices.remove(&id.device_id)
+                {
+                    removal = if session.is_authenticated()
+                        && !devices.values().any(|session| session.is_authenticated())
+                    {
+                        Removal::WentOffline
+                    } else {
+                        Removal::Removed
+                    };
+                }
+
+                devices.is_empty()
+            });
+
+        removal
     }
 
     pub async fn send_to_user(
@@ -33,37 +86,77 @@ impl SessionManager {
         public_key: &[u8],
         packet: Packet,
     ) -> Result<(), std::io::Error> {
-        if let Some(mut session) = self.sessions.get_mut(public_key) {
-            let requires_auth = !matches!(
-                packet,
-                Packet::Challenge { .. }
-                    | Packet::LoginResponse { .. }
-                    | Packet::MessageDelivered { .. }
-                    | Packet::ProfileUpdated { .. }
-                    | Packet::MessageReceived { .. }
-                    | Packet::Ping
-                    | Packet::Pong
-                    | Packet::SearchUser { .. }
-                    | Packet::UserFound { .. }
-                    | Packet::UserNotFound
-            );
-
-            if requires_auth && !session.authenticated {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::NotConnected,
-                    "Session not authenticated",
-                ));
+        let Some(mut devices) = self.sessions.get_mut(public_key) else {
+            return Err(user_not_found());
+        };
+
+        let requires_auth = requires_auth(&packet);
+        let raw = Arc::new(packet.to_raw());
+        let mut result = Err(user_not_found());
+
+        // Succeeds as long as at least one device took the packet.
+        for session in devices.values_mut() {
+            match enqueue_checked(session, requires_auth, Arc::clone(&raw)) {
+                Ok(()) => result = Ok(()),
+                Err(e) if result.is_err() => result = Err(e),
+                Err(_) => {}
             }
+        }
 
-            let raw = packet.to_raw();
-            raw.write_to(&mut session.write_half).await?;
-            session.update_activity();
-            Ok(())
-        } else {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "User not found",
-            ))
+        result
+    }
+
+    pub async fn send_to_device(
+        &self,
+        id: &SessionId,
+        packet: Packet,
+    ) -> Result<(), std::io::Error> {
+        let Some(mut devices) = self.sessions.get_mut(&id.public_key) else {
+            return Err(user_not_found());
+        };
+
+        let Some(session) = devices.get_mut(&id.device_id) else {
+            return Err(user_not_found());
+        };
+
+        enqueue_checked(session, requires_auth(&packet), Arc::new(packet.to_raw()))
+    }
+
+    /// Like `send_to_device`, but waits for room when the outbound queue is full. For backlogs
+    /// that can be larger than the queue, where dropping a packet is not an option.
+    pub async fn send_to_device_waiting(
+        &self,
+        id: &SessionId,
+        packet: Packet,
+    ) -> Result<(), std::io::Error> {
+        // The map guard must not be held across the wait.
+        let outbound = {
+            let Some(devices) = self.sessions.get(&id.public_key) else {
+                return Err(user_not_found());
+            };
+
+            let Some(session) = devices.get(&id.device_id) else {
+                return Err(user_not_found());
+            };
+
+            check_authenticated(session, requires_auth(&packet))?;
+
+            session.outbound()
+        };
+
+        match outbound
+            .send_timeout(Arc::new(packet.to_raw()), QUEUE_WAIT_TIMEOUT)
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(SendTimeoutError::Timeout(_)) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "Outbound queue stayed full",
+            )),
+            Err(SendTimeoutError::Closed(_)) => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "Session writer closed",
+            )),
         }
     }
 
@@ -72,12 +165,13 @@ impl SessionManager {
         public_keys: &[Vec<u8>],
         packet: Packet,
     ) -> Result<(), std::io::Error> {
-        let raw = packet.to_raw();
+        let raw = Arc::new(packet.to_raw());
 
         for pubkey in public_keys {
-            if let Some(mut session) = self.sessions.get_mut(pubkey) {
-                raw.write_to(&mut session.write_half).await?;
-                session.update_activity();
+            if let Some(mut devices) = self.sessions.get_mut(pubkey) {
+                for session in devices.values_mut() {
+                    let _ = session.enqueue(Arc::clone(&raw));
+                }
             }
         }
 
@@ -85,26 +179,120 @@ impl SessionManager {
     }
 
     pub async fn _broadcast(&self, packet: Packet) -> Result<(), std::io::Error> {
-        let raw = packet.to_raw();
+        let raw = Arc::new(packet.to_raw());
 
-        for mut session in self.sessions.iter_mut() {
-            raw.write_to(&mut session.write_half).await?;
-            session.update_activity();
+        for mut devices in self.sessions.iter_mut() {
+            for session in devices.values_mut() {
+                let _ = session.enqueue(Arc::clone(&raw));
+            }
         }
 
         Ok(())
     }
 
-    pub async fn set_authenticated(&self, public_key: &[u8]) {
-        if let Some(mut session) = self.sessions.get_mut(public_key) {
-            session.authenticated = true;
+    pub fn is_authenticated(&self, id: &SessionId) -> bool {
+        self.sessions
+            .get(&id.public_key)
+            .and_then(|devices| devices.get(&id.device_id).map(|session| session.is_authenticated()))
+            .unwrap_or(false)
+    }
+
+    /// Number of logged in devices of a user, temporary pre-login sessions are not counted.
+    pub fn online_devices(&self, public_key: &[u8]) -> usize {
+        self.sessions
+            .get(public_key)
+            .map(|devices| devices.values().filter(|session| session.is_authenticated()).count())
+            .unwrap_or(0)
+    }
+
+    /// All open sessions, and how many of them are logged in.
+    pub fn session_counts(&self) -> (usize, usize) {
+        self.sessions.iter().fold((0, 0), |(active, authenticated), devices| {
+            (
+                active + devices.len(),
+                authenticated + devices.values().filter(|session| session.is_authenticated()).count(),
+            )
+        })
+    }
+
+    /// Moves a connection's session to the device it logged in as, replacing an older session of
+    /// that device. Returns whether the user came online, decided under the same lock as the move
+    /// so two devices logging in at once announce it exactly once.
+    pub fn log_in(&self, old_id: &SessionId, new_id: SessionId) -> bool {
+        let session = self
+            .sessions
+            .get_mut(&old_id.public_key)
+            .and_then(|mut devices| devices.remove(&old_id.device_id));
+
+        self.sessions
+            .remove_if(&old_id.public_key, |_, devices| devices.is_empty());
+
+        let Some(mut session) = session else {
+            return false;
+        };
+
+        session.public_key = new_id.public_key;
+        session.device_id = new_id.device_id;
+        session.authenticated = true;
+
+        let mut devices = self.sessions.entry(session.public_key.clone()).or_default();
+        // A device logging in again replaces a logged in session, the user never went offline.
+        let was_online = devices.values().any(|session| session.is_authenticated());
+
+        if let Some(replaced) = devices.insert(session.device_id.clone(), session) {
+            replaced.close();
         }
+
+        !was_online
     }
 
-    pub async fn move_session(&self, old_key: &[u8], new_key: Vec<u8>) {
-        if let Some((_, mut session)) = self.sessions.remove(old_key) {
-            session.public_key = new_key.clone();
-            self.sessions.insert(new_key, session);
+    /// Tells the connection holding this device's session to close, if there is one.
+    pub fn close_session(&self, id: &SessionId) {
+        if let Some(devices) = self.sessions.get(&id.public_key)
+            && let Some(session) = devices.get(&id.device_id)
+        {
+            session.close();
+        }
+    }
+
+    pub fn touch(&self, id: &SessionId) {
+        if let Some(mut devices) = self.sessions.get_mut(&id.public_key)
+            && let Some(session) = devices.get_mut(&id.device_id)
+        {
+            session.update_activity();
+        }
+    }
+
+    /// Closes sessions idle for `idle_timeout` until the token is cancelled. Sessions idle for
+    /// `ping_after` get a single Ping first, so a live client answers and stays connected.
+    pub async fn run_reaper(
+        &self,
+        idle_timeout: Duration,
+        ping_after: Option<Duration>,
+        interval: Duration,
+        shutdown_token: CancellationToken,
+    ) {
+        let mut interval = tokio::time::interval(interval);
+        let ping = Arc::new(Packet::Ping.to_raw());
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_token.cancelled() => break,
+            }
+
+            for mut devices in self.sessions.iter_mut() {
+                for session in devices.values_mut() {
+                    let idle = session.last_activity.elapsed();
+
+                    if idle >= idle_timeout {
+                        session.close();
+                    } else if ping_after.is_some_and(|ping_after| idle >= ping_after) && !session.pinged {
+                        session.pinged = true;
+                        let _ = session.enqueue(Arc::clone(&ping));
+                    }
+                }
+            }
         }
     }
 
@@ -120,3 +308,117 @@ impl SessionManager {
         enc_pubkeys.get(&auth_pub_key).cloned()
     }
 }
+
+fn requires_auth(packet: &Packet) -> bool {
+    !matches!(
+        packet,
+        Packet::Challenge { .. }
+            | Packet::LoginResponse { .. }
+            | Packet::MessageDelivered { .. }
+            | Packet::ProfileUpdated { .. }
+            | Packet::MessageReceived { .. }
+            | Packet::Ping
+            | Packet::Pong
+            | Packet::SearchUser { .. }
+            | Packet::UserFound { .. }
+            | Packet::UserNotFound
+            | Packet::SearchResults { .. }
+            | Packet::RateLimited { .. }
+            | Packet::Error { .. }
+            | Packet::GoingAway
+    )
+}
+
+fn enqueue_checked(
+    session: &mut Session,
+    requires_auth: bool,
+    raw: Arc<RawPacket>,
+) -> Result<(), std::io::Error> {
+    check_authenticated(session, requires_auth)?;
+
+    session.enqueue(raw)
+}
+
+fn check_authenticated(session: &Session, requires_auth: bool) -> Result<(), std::io::Error> {
+    if requires_auth && !session.authenticated {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotConnected,
+            "Session not authenticated",
+        ));
+    }
+
+    Ok(())
+}
+
+fn user_not_found() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "User not found")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hnet::transport::StreamSink;
+    use tokio_util::task::TaskTracker;
+
+    // A pre-login session on a fresh connection, the way the server opens one.
+    fn connect(manager: &SessionManager, port: u16) -> (SessionId, ConnectionId) {
+        let id = SessionId::new(format!("127.0.0.1:{}", port).into_bytes(), Vec::new());
+        let connection = ConnectionId::next();
+        let (stream, _) = tokio::io::duplex(64);
+        let (_, writer) = tokio::io::split(stream);
+
+        manager.add_session(Session::new(
+            id.clone(),
+            connection,
+            StreamSink(writer),
+            CancellationToken::new(),
+            &TaskTracker::new(),
+        ));
+
+        (id, connection)
+    }
+
+    fn device(name: &[u8]) -> SessionId {
+        SessionId::new(b"user".to_vec(), name.to_vec())
+    }
+
+    #[tokio::test]
+    async fn user_goes_online_and_offline_once_across_devices() {
+        let manager = SessionManager::new(16);
+
+        let (phone, first_phone) = connect(&manager, 1);
+        assert!(manager.log_in(&phone, device(b"phone")));
+
+        let (laptop, laptop_connection) = connect(&manager, 2);
+        assert!(!manager.log_in(&laptop, device(b"laptop")));
+
+        // The phone logging in again replaces its own session without ever leaving.
+        let (phone, second_phone) = connect(&manager, 3);
+        assert!(!manager.log_in(&phone, device(b"phone")));
+        assert_eq!(
+            manager.remove_session(&device(b"phone"), first_phone),
+            Removal::Kept
+        );
+
+        assert_eq!(
+            manager.remove_session(&device(b"laptop"), laptop_connection),
+            Removal::Removed
+        );
+        assert_eq!(
+            manager.remove_session(&device(b"phone"), second_phone),
+            Removal::WentOffline
+        );
+
+        let (phone, _) = connect(&manager, 4);
+        assert!(manager.log_in(&phone, device(b"phone")));
+    }
+
+    #[tokio::test]
+    async fn closing_before_login_is_not_going_offline() {
+        let manager = SessionManager::new(16);
+        let (id, connection) = connect(&manager, 1);
+
+        assert_eq!(manager.remove_session(&id, connection), Removal::Removed);
+        assert_eq!(manager.remove_session(&id, connection), Removal::Kept);
+    }
+}
@@ -1,5 +1,5 @@
 mod manager;
 mod session;
 
-pub use manager::SessionManager;
-pub use session::Session;
+pub use manager::{Removal, SessionManager};
+pub use session::{ConnectionId, Session, SessionId};
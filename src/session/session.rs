@@ -1,29 +1,125 @@
+use crate::hnet::transport::PacketSink;
+use hnet_protocol::RawPacket;
+use serde::Serialize;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Instant;
-use tokio::io::WriteHalf;
-use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+const OUTBOUND_QUEUE_SIZE: usize = 256;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SessionId {
+    pub public_key: Vec<u8>,
+    pub device_id: Vec<u8>,
+}
+
+impl SessionId {
+    pub fn new(public_key: Vec<u8>, device_id: Vec<u8>) -> Self {
+        Self {
+            public_key,
+            device_id,
+        }
+    }
+}
+
+/// One client connection from accept to close. Unlike `SessionId` it stays the same across login.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub fn next() -> Self {
+        Self(NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
 
 pub struct Session {
     pub public_key: Vec<u8>,
-    pub write_half: WriteHalf<TcpStream>,
+    pub device_id: Vec<u8>,
+    /// The connection this session belongs to, a later login of the same device gets a new one.
+    pub connection: ConnectionId,
+    outbound: mpsc::Sender<Arc<RawPacket>>,
     pub authenticated: bool,
+    /// Last time the client sent anything, outbound traffic does not count.
     pub last_activity: Instant,
+    /// Set once the reaper pinged this idle session, cleared by the next inbound packet.
+    pub pinged: bool,
+    closed: CancellationToken,
 }
 
 impl Session {
-    pub fn new(public_key: Vec<u8>, write_half: WriteHalf<TcpStream>) -> Self {
+    /// The writer task is tracked so shutdown can wait for queued packets to reach the socket.
+    pub fn new(
+        id: SessionId,
+        connection: ConnectionId,
+        mut sink: impl PacketSink,
+        closed: CancellationToken,
+        tracker: &TaskTracker,
+    ) -> Self {
+        let (outbound, mut queue) = mpsc::channel::<Arc<RawPacket>>(OUTBOUND_QUEUE_SIZE);
+
+        tracker.spawn(async move {
+            while let Some(raw) = queue.recv().await {
+                if sink.write_packet(&raw).await.is_err() {
+                    break;
+                }
+            }
+        });
+
         Self {
-            public_key,
-            write_half,
+            public_key: id.public_key,
+            device_id: id.device_id,
+            connection,
+            outbound,
             authenticated: false,
             last_activity: Instant::now(),
+            pinged: false,
+            closed,
         }
     }
 
+    /// Queues a packet for the session's writer task without waiting on the socket.
+    pub fn enqueue(&mut self, raw: Arc<RawPacket>) -> Result<(), std::io::Error> {
+        match self.outbound.try_send(raw) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "Outbound queue full",
+            )),
+            Err(mpsc::error::TrySendError::Closed(_)) => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "Session writer closed",
+            )),
+        }
+    }
+
+    /// A handle on the outbound queue for senders that would rather wait for room than fail.
+    pub fn outbound(&self) -> mpsc::Sender<Arc<RawPacket>> {
+        self.outbound.clone()
+    }
+
     pub fn update_activity(&mut self) {
         self.last_activity = Instant::now();
+        self.pinged = false;
+    }
+
+    /// Asks the connection task that owns this session to shut it down.
+    pub fn close(&self) {
+        self.closed.cancel();
     }
 
-    pub fn _is_authenticated(&self) -> bool {
+    pub fn is_authenticated(&self) -> bool {
         self.authenticated
     }
 }